@@ -0,0 +1,174 @@
+use anyhow::{Result, bail};
+use serde_json::{Map, Value};
+use std::iter::Peekable;
+use std::str::Chars;
+
+// Parse a dotenv (.env) file into a flat JSON object of string values. Supported are:
+// - comments on their own line (# ...) and after unquoted values ( # ...)
+// - an optional "export " prefix before the key
+// - single quoted values (taken literally) and double quoted values (with escapes), both can span multiple lines
+pub fn parse(content: &str) -> Result<Value> {
+    let mut parser = Parser {
+        chars: content.chars().peekable(),
+        line: 1,
+    };
+    let mut obj = Map::new();
+
+    while let Some((key, value)) = parser.next_entry()? {
+        obj.insert(key, Value::String(value));
+    }
+
+    Ok(Value::Object(obj))
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+    }
+
+    // Skip the rest of the line, which may only contain whitespace or a comment
+    fn finish_line(&mut self, line: usize) -> Result<()> {
+        self.skip_spaces();
+        match self.next() {
+            None | Some('\n') => Ok(()),
+            Some('\r') if matches!(self.chars.peek(), None | Some('\n')) => {
+                self.next();
+                Ok(())
+            }
+            Some('#') => {
+                while !matches!(self.next(), None | Some('\n')) {}
+                Ok(())
+            }
+            Some(c) => bail!("Unexpected character '{c}' after quoted value at line {line}"),
+        }
+    }
+
+    fn next_entry(&mut self) -> Result<Option<(String, String)>> {
+        loop {
+            self.skip_spaces();
+            match self.chars.peek() {
+                None => return Ok(None),
+                Some('\n' | '\r') => {
+                    self.next();
+                }
+                Some('#') => self.finish_line(self.line)?,
+                Some(_) => break,
+            }
+        }
+
+        let line = self.line;
+        let mut key = self.read_word();
+        if key == "export" && matches!(self.chars.peek(), Some(' ' | '\t')) {
+            self.skip_spaces();
+            key = self.read_word();
+        }
+
+        if key.is_empty() {
+            bail!("Expected a variable name at line {line}");
+        }
+        if key.starts_with(|c: char| c.is_ascii_digit())
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+        {
+            bail!("Invalid variable name '{key}' at line {line}");
+        }
+
+        self.skip_spaces();
+        if self.next() != Some('=') {
+            bail!("Expected '=' after variable name '{key}' at line {line}");
+        }
+        self.skip_spaces();
+
+        let value = match self.chars.peek() {
+            Some('\'') => {
+                self.next();
+                let value = self.read_single_quoted(line)?;
+                self.finish_line(line)?;
+                value
+            }
+            Some('"') => {
+                self.next();
+                let value = self.read_double_quoted(line)?;
+                self.finish_line(line)?;
+                value
+            }
+            _ => self.read_unquoted(),
+        };
+
+        Ok(Some((key, value)))
+    }
+
+    fn read_word(&mut self) -> String {
+        let mut word = String::new();
+        while let Some(c) = self
+            .chars
+            .next_if(|c| !c.is_whitespace() && *c != '=' && *c != '#')
+        {
+            word.push(c);
+        }
+        word
+    }
+
+    fn read_single_quoted(&mut self, line: usize) -> Result<String> {
+        let mut value = String::new();
+        loop {
+            match self.next() {
+                Some('\'') => return Ok(value),
+                Some(c) => value.push(c),
+                None => bail!("Unterminated single quoted value starting at line {line}"),
+            }
+        }
+    }
+
+    fn read_double_quoted(&mut self, line: usize) -> Result<String> {
+        let mut value = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(value),
+                Some('\\') => match self.next() {
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some(c @ ('"' | '\\' | '$')) => value.push(c),
+                    Some(c) => {
+                        value.push('\\');
+                        value.push(c);
+                    }
+                    None => bail!("Unterminated double quoted value starting at line {line}"),
+                },
+                Some(c) => value.push(c),
+                None => bail!("Unterminated double quoted value starting at line {line}"),
+            }
+        }
+    }
+
+    // Unquoted values end at the end of the line, or at a comment that is preceded by whitespace
+    fn read_unquoted(&mut self) -> String {
+        let mut value = String::new();
+        while let Some(c) = self.next() {
+            match c {
+                '\n' => break,
+                '#' if value.is_empty() || value.ends_with([' ', '\t']) => {
+                    while !matches!(self.next(), None | Some('\n')) {}
+                    break;
+                }
+                _ => value.push(c),
+            }
+        }
+        value.trim_end().to_string()
+    }
+}
@@ -0,0 +1,249 @@
+use crate::dotenv;
+use anyhow::{Context, Result, anyhow, bail};
+use clap::ValueEnum;
+use serde::Deserialize;
+use serde_json::{Map, Number, Value};
+use std::fmt;
+use std::path::Path;
+
+// File formats that can be used for the schema, input and substitute files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+    Dotenv,
+}
+
+impl Format {
+    // Detect the format based on the file extension, falling back to JSON
+    pub fn from_path(path: &str) -> Format {
+        let path = Path::new(path);
+        let file_name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_lowercase);
+
+        // Dotenv files are often only named after their extension (e.g. ".env" or ".env.local")
+        if file_name == ".env" || file_name.starts_with(".env.") {
+            return Format::Dotenv;
+        }
+
+        match extension.as_deref() {
+            Some("yaml" | "yml") => Format::Yaml,
+            Some("toml") => Format::Toml,
+            Some("env") => Format::Dotenv,
+            _ => Format::Json,
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Json => write!(f, "JSON"),
+            Format::Yaml => write!(f, "YAML"),
+            Format::Toml => write!(f, "TOML"),
+            Format::Dotenv => write!(f, "dotenv"),
+        }
+    }
+}
+
+// Parse the contents of a file in the given format into a JSON value
+pub fn parse(content: &str, format: Format) -> Result<Value> {
+    match format {
+        Format::Json => Ok(serde_json::from_str(content)?),
+        Format::Yaml => parse_yaml(content),
+        Format::Toml => parse_toml(content),
+        Format::Dotenv => dotenv::parse(content),
+    }
+}
+
+// Parse the contents of a file that can hold multiple documents (e.g. "---" separated YAML)
+pub fn parse_documents(content: &str, format: Format) -> Result<Vec<Value>> {
+    match format {
+        Format::Json | Format::Toml | Format::Dotenv => Ok(vec![parse(content, format)?]),
+        Format::Yaml => {
+            let documents = serde_yaml_ng::Deserializer::from_str(content)
+                .enumerate()
+                .map(|(index, document)| {
+                    let yaml = serde_yaml_ng::Value::deserialize(document).map_err(yaml_error)?;
+                    yaml_to_json_document(yaml)
+                        .with_context(|| format!("Invalid YAML in document {index}"))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            // An empty file still is a single (null) document
+            if documents.is_empty() {
+                Ok(vec![Value::Null])
+            } else {
+                Ok(documents)
+            }
+        }
+    }
+}
+
+// Serialize documents to a string in the given format, only YAML can hold more than one document
+pub fn to_string(documents: &[Value], format: Format) -> Result<String> {
+    match (format, documents) {
+        (Format::Json, [document]) => Ok(serde_json::to_string_pretty(document)?),
+        (Format::Toml, [document]) => to_toml_string(document),
+        (Format::Dotenv, _) => bail!("Writing dotenv output is not supported"),
+        (Format::Json | Format::Toml, _) => bail!(
+            "{} documents cannot be written as a single {format} document, use YAML output instead",
+            documents.len()
+        ),
+        (Format::Yaml, _) => {
+            let mut output = String::new();
+            for (index, document) in documents.iter().enumerate() {
+                if index > 0 {
+                    output.push_str("---\n");
+                }
+                output.push_str(&serde_yaml_ng::to_string(document)?);
+            }
+            Ok(output)
+        }
+    }
+}
+
+fn parse_yaml(content: &str) -> Result<Value> {
+    let yaml: serde_yaml_ng::Value = serde_yaml_ng::from_str(content).map_err(yaml_error)?;
+    yaml_to_json_document(yaml)
+}
+
+// Add the location of a YAML syntax error, so that it can be found in the file
+fn yaml_error(e: serde_yaml_ng::Error) -> anyhow::Error {
+    let message = match e.location() {
+        Some(location) => format!(
+            "Invalid YAML at line {}, column {}",
+            location.line(),
+            location.column()
+        ),
+        None => "Invalid YAML".to_string(),
+    };
+    anyhow!(e).context(message)
+}
+
+fn yaml_to_json_document(mut yaml: serde_yaml_ng::Value) -> Result<Value> {
+    // Resolve merge keys (<<: *anchor), aliases are already resolved by the parser
+    yaml.apply_merge()
+        .context("Failed to apply YAML merge keys")?;
+
+    yaml_to_json(yaml, "$")
+}
+
+// Convert a YAML value to its JSON equivalent, YAML is a superset of JSON so not everything maps
+fn yaml_to_json(yaml: serde_yaml_ng::Value, yaml_path: &str) -> Result<Value> {
+    let json = match yaml {
+        serde_yaml_ng::Value::Null => Value::Null,
+        serde_yaml_ng::Value::Bool(b) => Value::Bool(b),
+        serde_yaml_ng::Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                Value::Number(i.into())
+            } else if let Some(u) = n.as_u64() {
+                Value::Number(u.into())
+            } else {
+                let f = n.as_f64().unwrap_or(f64::NAN);
+                Value::Number(Number::from_f64(f).ok_or_else(|| {
+                    anyhow!("Number '{n}' at path '{yaml_path}' cannot be represented in JSON")
+                })?)
+            }
+        }
+        serde_yaml_ng::Value::String(s) => Value::String(s),
+        serde_yaml_ng::Value::Sequence(seq) => Value::Array(
+            seq.into_iter()
+                .enumerate()
+                .map(|(index, item)| yaml_to_json(item, &format!("{yaml_path}[{index}]")))
+                .collect::<Result<_>>()?,
+        ),
+        serde_yaml_ng::Value::Mapping(mapping) => {
+            let mut obj = Map::new();
+            for (key, value) in mapping {
+                let key = match key {
+                    serde_yaml_ng::Value::String(s) => s,
+                    serde_yaml_ng::Value::Bool(b) => b.to_string(),
+                    serde_yaml_ng::Value::Number(n) => n.to_string(),
+                    serde_yaml_ng::Value::Null => "null".to_string(),
+                    _ => bail!(
+                        "Mapping at path '{yaml_path}' has a key that is not a string, number or boolean"
+                    ),
+                };
+                let value = yaml_to_json(value, &format!("{yaml_path}.{key}"))?;
+                obj.insert(key, value);
+            }
+            Value::Object(obj)
+        }
+        // Tags (e.g. !Ref) have no JSON equivalent, so only the tagged value is kept
+        serde_yaml_ng::Value::Tagged(tagged) => yaml_to_json(tagged.value, yaml_path)?,
+    };
+
+    Ok(json)
+}
+
+fn parse_toml(content: &str) -> Result<Value> {
+    let table: toml::Table = toml::from_str(content)?;
+    toml_to_json(toml::Value::Table(table), "$")
+}
+
+// Convert a TOML value to its JSON equivalent, datetimes become strings so that they can be
+// validated with the "date-time", "date" and "time" formats of JSON schema
+fn toml_to_json(toml: toml::Value, toml_path: &str) -> Result<Value> {
+    let json = match toml {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::Number(i.into()),
+        toml::Value::Float(f) => Value::Number(Number::from_f64(f).ok_or_else(|| {
+            anyhow!("Number '{f}' at path '{toml_path}' cannot be represented in JSON")
+        })?),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(datetime) => Value::String(datetime.to_string()),
+        toml::Value::Array(arr) => Value::Array(
+            arr.into_iter()
+                .enumerate()
+                .map(|(index, item)| toml_to_json(item, &format!("{toml_path}[{index}]")))
+                .collect::<Result<_>>()?,
+        ),
+        toml::Value::Table(table) => Value::Object(
+            table
+                .into_iter()
+                .map(|(key, value)| {
+                    let value = toml_to_json(value, &format!("{toml_path}.{key}"))?;
+                    Ok((key, value))
+                })
+                .collect::<Result<_>>()?,
+        ),
+    };
+
+    Ok(json)
+}
+
+fn to_toml_string(document: &Value) -> Result<String> {
+    if !document.is_object() {
+        bail!("Only an object can be written as TOML document");
+    }
+
+    // TOML has no null, so refuse to silently drop these values
+    if let Some(json_path) = find_null(document, "$") {
+        bail!("Value at path '{json_path}' is null, which cannot be represented in TOML");
+    }
+
+    Ok(toml::to_string_pretty(document)?)
+}
+
+// Find the JSON path of the first null value in a document, if any
+fn find_null(value: &Value, json_path: &str) -> Option<String> {
+    match value {
+        Value::Null => Some(json_path.to_string()),
+        Value::Object(obj) => obj
+            .iter()
+            .find_map(|(key, v)| find_null(v, &format!("{json_path}.{key}"))),
+        Value::Array(arr) => arr
+            .iter()
+            .enumerate()
+            .find_map(|(index, item)| find_null(item, &format!("{json_path}[{index}]"))),
+        _ => None,
+    }
+}
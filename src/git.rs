@@ -0,0 +1,535 @@
+use anyhow::{Context, Result, bail};
+use flate2::read::ZlibDecoder;
+use regex::Regex;
+use serde_json::{Map, Value};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::cell::OnceCell;
+use std::cmp::Ordering;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::time::UNIX_EPOCH;
+use tracing::{debug, warn};
+
+// Refs and tags can point to each other, so stop following them at some point
+const MAX_REF_DEPTH: usize = 10;
+const SHORT_SHA_LENGTH: usize = 7;
+
+// Index entry flags, see https://git-scm.com/docs/index-format
+const FLAG_ASSUME_VALID: u16 = 0x8000;
+const FLAG_EXTENDED: u16 = 0x4000;
+const FLAG_SKIP_WORKTREE: u16 = 0x4000;
+const MODE_TYPE: u32 = 0o170_000;
+const MODE_SYMLINK: u32 = 0o120_000;
+const MODE_GITLINK: u32 = 0o160_000;
+
+// Runs of digits and of everything else in a tag name, to compare them like versions
+static VERSION_PARTS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\d+|\D+").expect("valid regex"));
+
+// Metadata of the git repository that contains the input file, for `git.` placeholders. It is read from the
+// `.git` directory directly (git itself is not needed) when a placeholder first asks for it.
+pub struct Git {
+    start_dir: PathBuf,
+    metadata: OnceCell<Option<Value>>,
+}
+
+impl Git {
+    pub fn new(start_dir: PathBuf) -> Self {
+        Git {
+            start_dir,
+            metadata: OnceCell::new(),
+        }
+    }
+
+    // An object with the sha, short_sha, branch, tag and dirty fields that apply, None outside a repository
+    pub fn metadata(&self) -> Option<&Value> {
+        self.metadata
+            .get_or_init(|| match Repository::discover(&self.start_dir) {
+                Ok(Some(repository)) => match repository.metadata() {
+                    Ok(metadata) => Some(metadata),
+                    Err(e) => {
+                        warn!(
+                            "Failed to read git repository {}: {e:#}",
+                            repository.work_tree.display()
+                        );
+                        None
+                    }
+                },
+                Ok(None) => {
+                    debug!("No git repository found for {}", self.start_dir.display());
+                    None
+                }
+                Err(e) => {
+                    warn!("Failed to find git repository: {e:#}");
+                    None
+                }
+            })
+            .as_ref()
+    }
+}
+
+struct Repository {
+    // HEAD and the index are in the git directory, refs and objects in the common directory. These only differ
+    // for linked worktrees.
+    git_dir: PathBuf,
+    common_dir: PathBuf,
+    work_tree: PathBuf,
+}
+
+struct PackedRef {
+    name: String,
+    sha: String,
+    // The commit an annotated tag points to, when known
+    peeled: Option<String>,
+}
+
+impl Repository {
+    // Find the repository in the given directory or one of its parents
+    fn discover(start_dir: &Path) -> Result<Option<Self>> {
+        let start_dir = start_dir
+            .canonicalize()
+            .with_context(|| format!("Failed to read directory: {}", start_dir.display()))?;
+
+        for dir in start_dir.ancestors() {
+            let dot_git = dir.join(".git");
+            let git_dir = if dot_git.is_dir() {
+                dot_git
+            } else if dot_git.is_file() {
+                // Linked worktrees and submodules have a `.git` file that points to their git directory
+                let contents = read_trimmed(&dot_git)?;
+                let Some(path) = contents.strip_prefix("gitdir:") else {
+                    bail!("Invalid .git file: {}", dot_git.display());
+                };
+                dir.join(path.trim())
+            } else {
+                continue;
+            };
+
+            let common_dir = match read_trimmed(&git_dir.join("commondir")) {
+                Ok(path) => git_dir.join(path),
+                Err(_) => git_dir.clone(),
+            };
+            debug!("Found git repository in {}", dir.display());
+            return Ok(Some(Repository {
+                git_dir,
+                common_dir,
+                work_tree: dir.to_path_buf(),
+            }));
+        }
+        Ok(None)
+    }
+
+    fn metadata(&self) -> Result<Value> {
+        let mut metadata = Map::new();
+
+        let head = read_trimmed(&self.git_dir.join("HEAD"))?;
+        let sha = if let Some(name) = head.strip_prefix("ref:") {
+            let name = name.trim();
+            if let Some(branch) = name.strip_prefix("refs/heads/") {
+                metadata.insert("branch".to_string(), Value::String(branch.to_string()));
+            }
+            self.resolve_ref(name, 0)?
+        } else {
+            // A detached HEAD contains the sha itself
+            Some(head)
+        };
+
+        // A branch without commits has no sha, tag or changes yet
+        let Some(sha) = sha else {
+            return Ok(Value::Object(metadata));
+        };
+        if let Some(tag) = self.tag(&sha)? {
+            metadata.insert("tag".to_string(), Value::String(tag));
+        }
+        metadata.insert("dirty".to_string(), Value::Bool(self.dirty(sha.len() / 2)?));
+        metadata.insert(
+            "short_sha".to_string(),
+            Value::String(sha.chars().take(SHORT_SHA_LENGTH).collect()),
+        );
+        metadata.insert("sha".to_string(), Value::String(sha));
+
+        Ok(Value::Object(metadata))
+    }
+
+    // The sha a ref (e.g. "refs/heads/main") points to, loose refs take precedence over packed ones
+    fn resolve_ref(&self, name: &str, depth: usize) -> Result<Option<String>> {
+        if depth > MAX_REF_DEPTH {
+            bail!("Git ref '{name}' refers to other refs more than {MAX_REF_DEPTH} levels deep");
+        }
+
+        match read_trimmed(&self.common_dir.join(name)) {
+            Ok(contents) => match contents.strip_prefix("ref:") {
+                Some(target) => self.resolve_ref(target.trim(), depth + 1),
+                None => Ok(Some(contents)),
+            },
+            Err(e) if is_not_found(&e) => Ok(self
+                .packed_refs()?
+                .into_iter()
+                .find(|packed_ref| packed_ref.name == name)
+                .map(|packed_ref| packed_ref.sha)),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn packed_refs(&self) -> Result<Vec<PackedRef>> {
+        let contents = match read_trimmed(&self.common_dir.join("packed-refs")) {
+            Ok(contents) => contents,
+            Err(e) if is_not_found(&e) => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut packed_refs: Vec<PackedRef> = Vec::new();
+        for line in contents.lines() {
+            if line.starts_with('#') {
+                continue;
+            }
+            // A peeled line follows the annotated tag it belongs to
+            if let Some(peeled) = line.strip_prefix('^') {
+                if let Some(packed_ref) = packed_refs.last_mut() {
+                    packed_ref.peeled = Some(peeled.trim().to_string());
+                }
+            } else if let Some((sha, name)) = line.split_once(' ') {
+                packed_refs.push(PackedRef {
+                    name: name.trim().to_string(),
+                    sha: sha.to_string(),
+                    peeled: None,
+                });
+            }
+        }
+        Ok(packed_refs)
+    }
+
+    // The tag that points to the given commit. When there are several, the highest version wins.
+    fn tag(&self, sha: &str) -> Result<Option<String>> {
+        let mut tags = Vec::new();
+        for packed_ref in self.packed_refs()? {
+            if let Some(name) = packed_ref.name.strip_prefix("refs/tags/") {
+                tags.push((name.to_string(), packed_ref.sha, packed_ref.peeled));
+            }
+        }
+        let mut loose_tags = Vec::new();
+        find_loose_refs(&self.common_dir.join("refs/tags"), "", &mut loose_tags)?;
+        for (name, tag_sha) in loose_tags {
+            tags.retain(|(other, _, _)| *other != name);
+            tags.push((name, tag_sha, None));
+        }
+
+        let mut matching = Vec::new();
+        for (name, tag_sha, peeled) in tags {
+            let target = match peeled {
+                Some(peeled) => Some(peeled),
+                None if tag_sha == sha => Some(tag_sha),
+                None => self.peel(&tag_sha, 0)?,
+            };
+            if target.as_deref() == Some(sha) {
+                matching.push(name);
+            }
+        }
+
+        matching.sort_by(|a, b| compare_versions(a, b));
+        Ok(matching.pop())
+    }
+
+    // The commit an annotated tag object points to. Only loose objects can be read, so tags whose object is
+    // packed are not peeled. A packed object that a tag points to is taken to be the commit, as `git gc` packs
+    // the commits that later tags are made for.
+    fn peel(&self, sha: &str, depth: usize) -> Result<Option<String>> {
+        if depth > MAX_REF_DEPTH || sha.len() < 3 {
+            return Ok(None);
+        }
+        let path = self
+            .common_dir
+            .join("objects")
+            .join(&sha[..2])
+            .join(&sha[2..]);
+        let compressed = match fs::read(&path) {
+            Ok(compressed) => compressed,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && depth > 0 => {
+                return Ok(Some(sha.to_string()));
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!("Git object {sha} is packed, so it cannot be peeled");
+                return Ok(None);
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read git object: {}", path.display()));
+            }
+        };
+
+        let mut object = Vec::new();
+        ZlibDecoder::new(compressed.as_slice())
+            .read_to_end(&mut object)
+            .with_context(|| format!("Failed to decompress git object: {}", path.display()))?;
+        let object = String::from_utf8_lossy(&object);
+
+        if !object.starts_with("tag ") {
+            return Ok(Some(sha.to_string()));
+        }
+        let target = object
+            .split_once('\0')
+            .and_then(|(_, body)| body.lines().next())
+            .and_then(|line| line.strip_prefix("object "));
+        match target {
+            Some(target) => self.peel(target.trim(), depth + 1),
+            None => Ok(None),
+        }
+    }
+
+    // Whether tracked files in the work tree differ from the index. Untracked files are not taken into account,
+    // like `git describe --dirty`. Files whose size and modification time match the index are assumed to be
+    // unchanged, others are hashed to compare them with the index.
+    fn dirty(&self, hash_size: usize) -> Result<bool> {
+        let path = self.git_dir.join("index");
+        let index = match fs::read(&path) {
+            Ok(index) => index,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read git index: {}", path.display()));
+            }
+        };
+
+        if index.get(..4) != Some(b"DIRC".as_slice()) {
+            bail!("Invalid git index: {}", path.display());
+        }
+        let version = read_u32(&index, 4)?;
+        if !(2..=4).contains(&version) {
+            bail!(
+                "Unsupported git index version {version}: {}",
+                path.display()
+            );
+        }
+
+        let mut offset = 12;
+        let mut previous_path = Vec::new();
+        for _ in 0..read_u32(&index, 8)? {
+            let entry = IndexEntry::parse(&index, &mut offset, version, hash_size, &previous_path)?;
+            if entry.changed(&self.work_tree)? {
+                debug!(
+                    "Git work tree is dirty, {} was changed",
+                    String::from_utf8_lossy(&entry.path)
+                );
+                return Ok(true);
+            }
+            previous_path = entry.path;
+        }
+        Ok(false)
+    }
+}
+
+struct IndexEntry {
+    mtime_seconds: u32,
+    mtime_nanoseconds: u32,
+    mode: u32,
+    size: u32,
+    sha: Vec<u8>,
+    flags: u16,
+    extended_flags: u16,
+    path: Vec<u8>,
+}
+
+impl IndexEntry {
+    fn parse(
+        index: &[u8],
+        offset: &mut usize,
+        version: u32,
+        hash_size: usize,
+        previous_path: &[u8],
+    ) -> Result<Self> {
+        let start = *offset;
+        let flags_offset = start + 40 + hash_size;
+        let flags = read_u16(index, flags_offset)?;
+        let mut path_offset = flags_offset + 2;
+        let extended_flags = if flags & FLAG_EXTENDED != 0 {
+            path_offset += 2;
+            read_u16(index, flags_offset + 2)?
+        } else {
+            0
+        };
+
+        // Version 4 only stores the part of the path that differs from the previous entry
+        let (mut path, path_offset) = if version == 4 {
+            let (strip, path_offset) = read_varint(index, path_offset)?;
+            let keep = previous_path
+                .len()
+                .checked_sub(strip)
+                .context("Invalid path in git index")?;
+            (previous_path[..keep].to_vec(), path_offset)
+        } else {
+            (Vec::new(), path_offset)
+        };
+        let path_end = index[path_offset.min(index.len())..]
+            .iter()
+            .position(|b| *b == 0)
+            .map(|position| path_offset + position)
+            .context("Truncated git index")?;
+        path.extend_from_slice(&index[path_offset..path_end]);
+
+        // Earlier versions pad entries with 1 to 8 NUL bytes to a multiple of 8
+        *offset = if version == 4 {
+            path_end + 1
+        } else {
+            start + ((path_end - start + 8) & !7)
+        };
+
+        Ok(IndexEntry {
+            mtime_seconds: read_u32(index, start + 8)?,
+            mtime_nanoseconds: read_u32(index, start + 12)?,
+            mode: read_u32(index, start + 24)?,
+            size: read_u32(index, start + 36)?,
+            sha: index[start + 40..start + 40 + hash_size].to_vec(),
+            flags,
+            extended_flags,
+            path,
+        })
+    }
+
+    fn changed(&self, work_tree: &Path) -> Result<bool> {
+        // Unmerged entries (stage 1 to 3) mean a merge is in progress
+        if (self.flags >> 12) & 3 != 0 {
+            return Ok(true);
+        }
+        if self.flags & FLAG_ASSUME_VALID != 0
+            || self.extended_flags & FLAG_SKIP_WORKTREE != 0
+            || self.mode & MODE_TYPE == MODE_GITLINK
+        {
+            return Ok(false);
+        }
+
+        let path = work_tree.join(String::from_utf8_lossy(&self.path).as_ref());
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+
+        // The index stores the lower 32 bits of sizes and times, and older versions of git no nanoseconds
+        #[allow(clippy::cast_possible_truncation)]
+        let size = metadata.len() as u32;
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        #[allow(clippy::cast_possible_truncation)]
+        let same_mtime = mtime.as_secs() as u32 == self.mtime_seconds
+            && (self.mtime_nanoseconds == 0 || mtime.subsec_nanos() == self.mtime_nanoseconds);
+        if size == self.size && same_mtime {
+            return Ok(false);
+        }
+
+        let contents = if self.mode & MODE_TYPE == MODE_SYMLINK {
+            fs::read_link(&path)?
+                .to_string_lossy()
+                .into_owned()
+                .into_bytes()
+        } else {
+            fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?
+        };
+        Ok(blob_hash(&contents, self.sha.len()) != self.sha)
+    }
+}
+
+// The object id git gives a file with these contents, using SHA-1 or (for SHA-256 repositories) SHA-256
+fn blob_hash(contents: &[u8], hash_size: usize) -> Vec<u8> {
+    let header = format!("blob {}\0", contents.len());
+    if hash_size == 32 {
+        Sha256::new()
+            .chain_update(header)
+            .chain_update(contents)
+            .finalize()
+            .to_vec()
+    } else {
+        Sha1::new()
+            .chain_update(header)
+            .chain_update(contents)
+            .finalize()
+            .to_vec()
+    }
+}
+
+// Collect the loose refs in a directory, e.g. tags named `v1.0` or `releases/v1.0`
+fn find_loose_refs(dir: &Path, prefix: &str, refs: &mut Vec<(String, String)>) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let name = format!("{prefix}{}", entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            find_loose_refs(&entry.path(), &format!("{name}/"), refs)?;
+        } else {
+            refs.push((name, read_trimmed(&entry.path())?));
+        }
+    }
+    Ok(())
+}
+
+// Compare tag names like versions, so that `v1.10.0` comes after `v1.9.0`
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let mut a_parts = VERSION_PARTS.find_iter(a).map(|part| part.as_str());
+    let mut b_parts = VERSION_PARTS.find_iter(b).map(|part| part.as_str());
+
+    loop {
+        let ordering = match (a_parts.next(), b_parts.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(a), Some(b)) => match (a.parse::<u64>(), b.parse::<u64>()) {
+                (Ok(a), Ok(b)) => a.cmp(&b),
+                _ => a.cmp(b),
+            },
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+fn read_trimmed(path: &Path) -> Result<String> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(contents.trim().to_string())
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == io::ErrorKind::NotFound)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .context("Truncated git index")?;
+    Ok(u32::from_be_bytes(bytes.try_into()?))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    let bytes = data
+        .get(offset..offset + 2)
+        .context("Truncated git index")?;
+    Ok(u16::from_be_bytes(bytes.try_into()?))
+}
+
+// The variable-length integers of index version 4, returned with the offset after them
+fn read_varint(data: &[u8], mut offset: usize) -> Result<(usize, usize)> {
+    let mut next = || {
+        let byte = *data.get(offset).context("Truncated git index")?;
+        offset += 1;
+        anyhow::Ok(byte)
+    };
+
+    let mut byte = next()?;
+    let mut value = usize::from(byte & 0x7f);
+    while byte & 0x80 != 0 {
+        byte = next()?;
+        value = ((value + 1) << 7) | usize::from(byte & 0x7f);
+    }
+    Ok((value, offset))
+}
@@ -1,12 +1,25 @@
+mod dotenv;
+mod format;
+mod git;
+mod schema;
+mod substitute;
+mod template;
+
 use anyhow::{Context, Result, anyhow, bail};
-use clap::{Arg, Command};
-use jsonschema::ValidationError;
-use regex::Regex;
+use clap::{Arg, ArgAction, ArgMatches, Command, value_parser};
+use format::Format;
+use git::Git;
+use jsonschema::{ValidationError, Validator};
+use schema::SchemaCursor;
 use serde_json::Value;
 use std::collections::HashMap;
-use std::fmt::Write;
+use std::fmt::{self, Write};
 use std::fs;
-use tracing::{debug, error, info};
+use std::io::{self, Read, Write as _};
+use std::path::{Path, PathBuf};
+use substitute::Substitutes;
+use template::Syntax;
+use tracing::{debug, error, info, warn};
 
 //
 // Global to reuse cargo.toml metadata
@@ -17,13 +30,27 @@ const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
 const DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
 // Argument IDs
 const ARG_SCHEMA: &str = "schema";
+const ARG_DOCUMENT_SCHEMA: &str = "document-schema";
+const ARG_DISCRIMINATOR: &str = "discriminator";
 const ARG_INPUT: &str = "input";
+const ARG_INPUT_FORMAT: &str = "input-format";
 const ARG_ENV_VARS_INPUT: &str = "env-vars";
+const ARG_ENV_VARS_FORMAT: &str = "env-vars-format";
 const ARG_ENV_SECRETS_INPUT: &str = "env-secrets";
+const ARG_ENV_SECRETS_FORMAT: &str = "env-secrets-format";
+const ARG_ENV_SECRETS_DIR: &str = "env-secrets-dir";
+const ARG_ENV_FROM_PROCESS: &str = "env-from-process";
+const ARG_SECRETS_FROM_PROCESS: &str = "secrets-from-process";
+const ARG_CASE_SENSITIVE: &str = "case-sensitive";
+const ARG_EXPAND_SUBSTITUTES: &str = "expand-substitutes";
+const ARG_PLACEHOLDER_SYNTAX: &str = "placeholder-syntax";
+const ARG_FILE_ROOT: &str = "file-root";
 const ARG_OUTPUT: &str = "output";
+const ARG_OUTPUT_FORMAT: &str = "output-format";
 
-fn main() -> Result<()> {
-    let matches = Command::new(NAME)
+#[allow(clippy::too_many_lines)] // Every argument is a few lines, splitting them up would not help readability
+fn cli() -> Command {
+    Command::new(NAME)
         .version(VERSION)
         .author(AUTHORS)
         .about(DESCRIPTION)
@@ -32,70 +59,299 @@ fn main() -> Result<()> {
                 .short('s')
                 .long("schema")
                 .value_name("FILE")
-                .help("Path to JSON schema to validate against")
+                .help("Path to JSON or YAML schema to validate against")
                 .required(true),
         )
+        .arg(
+            Arg::new(ARG_DOCUMENT_SCHEMA)
+                .long("document-schema")
+                .value_name("VALUE=FILE")
+                .action(ArgAction::Append)
+                .requires(ARG_DISCRIMINATOR)
+                .help("Schema to use for documents whose discriminator field equals VALUE (can be repeated)"),
+        )
+        .arg(
+            Arg::new(ARG_DISCRIMINATOR)
+                .long("discriminator")
+                .value_name("FIELD")
+                .help("Top-level field of each document used to select a --document-schema (e.g. kind)"),
+        )
         .arg(
             Arg::new(ARG_INPUT)
                 .short('i')
                 .long("input")
                 .value_name("FILE")
-                .help("Path to input JSON file that needs to be validated")
+                .help("Path to input JSON, YAML, TOML or dotenv file that needs to be validated, or '-' for stdin")
                 .required(true),
         )
+        .arg(
+            Arg::new(ARG_INPUT_FORMAT)
+                .long("input-format")
+                .value_name("FORMAT")
+                .value_parser(value_parser!(Format))
+                .help("Format of the input file (detected from the file extension if omitted)"),
+        )
         .arg(
             Arg::new(ARG_ENV_VARS_INPUT)
                 .short('e')
                 .long("env-vars")
                 .value_name("FILE")
+                .action(ArgAction::Append)
                 .help(
-                    "Path to JSON file that contains all environment variables (key, value) pairs",
+                    "Path to JSON, YAML, TOML or dotenv file that contains environment variables (key, value) pairs, or '-', env:VARNAME or fd:N (can be repeated, later files take precedence)",
                 ),
         )
+        .arg(
+            Arg::new(ARG_ENV_VARS_FORMAT)
+                .long("env-vars-format")
+                .value_name("FORMAT")
+                .value_parser(value_parser!(Format))
+                .help("Format of all environment variables files (detected from the file extension if omitted)"),
+        )
         .arg(
             Arg::new(ARG_ENV_SECRETS_INPUT)
                 .short('x')
                 .long("env-secrets")
                 .value_name("FILE")
-                .help("Path to JSON file that contains all environment secrets (key, value) pairs"),
+                .action(ArgAction::Append)
+                .help(
+                    "Path to JSON, YAML, TOML or dotenv file that contains environment secrets (key, value) pairs, or '-', env:VARNAME or fd:N (can be repeated, later files take precedence)",
+                ),
+        )
+        .arg(
+            Arg::new(ARG_ENV_SECRETS_FORMAT)
+                .long("env-secrets-format")
+                .value_name("FORMAT")
+                .value_parser(value_parser!(Format))
+                .help("Format of all environment secrets files (detected from the file extension if omitted)"),
+        )
+        .arg(
+            Arg::new(ARG_ENV_SECRETS_DIR)
+                .long("env-secrets-dir")
+                .value_name("DIR")
+                .action(ArgAction::Append)
+                .help("Path to a directory with one file per environment secret, named after its key (e.g. /run/secrets)"),
+        )
+        .arg(
+            Arg::new(ARG_ENV_FROM_PROCESS)
+                .long("env-from-process")
+                .value_name("PREFIX")
+                .num_args(0..=1)
+                .default_missing_value("")
+                .help("Use the environment of this process as environment variables, optionally only those starting with PREFIX (which is stripped)"),
+        )
+        .arg(
+            Arg::new(ARG_SECRETS_FROM_PROCESS)
+                .long("secrets-from-process")
+                .value_name("PREFIX")
+                .num_args(0..=1)
+                .default_missing_value("")
+                .help("Use the environment of this process as environment secrets, optionally only those starting with PREFIX (which is stripped)"),
+        )
+        .arg(
+            Arg::new(ARG_CASE_SENSITIVE)
+                .long("case-sensitive")
+                .action(ArgAction::SetTrue)
+                .help("Match substitute keys and placeholder names case-sensitively, instead of ignoring case"),
+        )
+        .arg(
+            Arg::new(ARG_EXPAND_SUBSTITUTES)
+                .long("expand-substitutes")
+                .value_name("MAX_DEPTH")
+                .num_args(0..=1)
+                .default_missing_value("10")
+                .value_parser(value_parser!(usize))
+                .help("Substitute placeholders in the values of substitutes themselves, nested up to MAX_DEPTH levels (10 if omitted)"),
+        )
+        .arg(
+            Arg::new(ARG_PLACEHOLDER_SYNTAX)
+                .long("placeholder-syntax")
+                .value_name("SYNTAX")
+                .value_parser(Syntax::parse)
+                .help("Placeholder delimiters: 'mustache' for {{ name }} (default), 'shell' for ${name} or custom ones like '<% %>'"),
+        )
+        .arg(
+            Arg::new(ARG_FILE_ROOT)
+                .long("file-root")
+                .value_name("DIR")
+                .help("Directory that file: placeholders cannot reach outside of (defaults to the directory of the input file)"),
         )
         .arg(
             Arg::new(ARG_OUTPUT)
                 .short('o')
                 .long("output")
                 .value_name("FILE")
-                .help("Path to write the validated and substituted JSON to"),
+                .help("Path to write the validated and substituted JSON, YAML or TOML to, or '-' for stdout"),
+        )
+        .arg(
+            Arg::new(ARG_OUTPUT_FORMAT)
+                .long("output-format")
+                .value_name("FORMAT")
+                .value_parser(value_parser!(Format))
+                .help("Format of the output file (detected from the file extension if omitted, the input format for stdout)"),
         )
-        .get_matches();
+}
+
+// A place to read substitutes from
+#[derive(Debug, Clone, PartialEq)]
+enum SubstituteSource {
+    File {
+        path: String,
+        format: Option<Format>,
+    },
+    Directory {
+        path: String,
+    },
+    Process {
+        prefix: String,
+    },
+}
+
+impl fmt::Display for SubstituteSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubstituteSource::File { path, .. } | SubstituteSource::Directory { path } => {
+                write!(f, "{path}")
+            }
+            SubstituteSource::Process { prefix } if prefix.is_empty() => {
+                write!(f, "the process environment")
+            }
+            SubstituteSource::Process { prefix } => {
+                write!(f, "the process environment (prefix {prefix})")
+            }
+        }
+    }
+}
 
-    // Set up simple logging to stdout
-    tracing_subscriber::fmt()
+// All options for a single run, collected from the command line
+#[derive(Debug, Default)]
+struct RunOptions {
+    schema_path: String,
+    document_schema_paths: Vec<(String, String)>,
+    discriminator: Option<String>,
+    input_path: String,
+    input_format: Option<Format>,
+    env_vars_sources: Vec<SubstituteSource>,
+    env_secrets_sources: Vec<SubstituteSource>,
+    case_sensitive: bool,
+    max_expansion_depth: Option<usize>,
+    placeholder_syntax: Syntax,
+    file_root: Option<String>,
+    output_path: Option<String>,
+    output_format: Option<Format>,
+}
+
+impl RunOptions {
+    fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        // Is already done by CLAP, but the type safety is nice
+        let schema_path = matches
+            .get_one::<String>(ARG_SCHEMA)
+            .ok_or_else(|| anyhow!("Schema argument is required"))?;
+        let input_path = matches
+            .get_one::<String>(ARG_INPUT)
+            .ok_or_else(|| anyhow!("Input argument is required"))?;
+
+        let document_schema_paths = matches
+            .get_many::<String>(ARG_DOCUMENT_SCHEMA)
+            .unwrap_or_default()
+            .map(|arg| {
+                arg.split_once('=')
+                    .map(|(value, path)| (value.to_string(), path.to_string()))
+                    .ok_or_else(|| anyhow!("Document schema '{arg}' must be given as VALUE=FILE"))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(RunOptions {
+            schema_path: schema_path.clone(),
+            document_schema_paths,
+            discriminator: matches.get_one::<String>(ARG_DISCRIMINATOR).cloned(),
+            input_path: input_path.clone(),
+            input_format: matches.get_one::<Format>(ARG_INPUT_FORMAT).copied(),
+            env_vars_sources: substitute_sources(
+                matches,
+                ARG_ENV_VARS_INPUT,
+                ARG_ENV_VARS_FORMAT,
+                None,
+                ARG_ENV_FROM_PROCESS,
+            ),
+            env_secrets_sources: substitute_sources(
+                matches,
+                ARG_ENV_SECRETS_INPUT,
+                ARG_ENV_SECRETS_FORMAT,
+                Some(ARG_ENV_SECRETS_DIR),
+                ARG_SECRETS_FROM_PROCESS,
+            ),
+            case_sensitive: matches.get_flag(ARG_CASE_SENSITIVE),
+            max_expansion_depth: matches.get_one::<usize>(ARG_EXPAND_SUBSTITUTES).copied(),
+            placeholder_syntax: matches
+                .get_one::<Syntax>(ARG_PLACEHOLDER_SYNTAX)
+                .cloned()
+                .unwrap_or_default(),
+            file_root: matches.get_one::<String>(ARG_FILE_ROOT).cloned(),
+            output_path: matches.get_one::<String>(ARG_OUTPUT).cloned(),
+            output_format: matches.get_one::<Format>(ARG_OUTPUT_FORMAT).copied(),
+        })
+    }
+}
+
+// Collect all substitute sources of one kind in the order in which they were given on the command line
+fn substitute_sources(
+    matches: &ArgMatches,
+    files_arg: &str,
+    format_arg: &str,
+    dir_arg: Option<&str>,
+    process_arg: &str,
+) -> Vec<SubstituteSource> {
+    let format = matches.get_one::<Format>(format_arg).copied();
+
+    let files = matches
+        .get_many::<String>(files_arg)
+        .unwrap_or_default()
+        .zip(matches.indices_of(files_arg).unwrap_or_default())
+        .map(|(path, index)| {
+            let source = SubstituteSource::File {
+                path: path.clone(),
+                format,
+            };
+            (index, source)
+        });
+    let dirs = dir_arg.into_iter().flat_map(|dir_arg| {
+        matches
+            .get_many::<String>(dir_arg)
+            .unwrap_or_default()
+            .zip(matches.indices_of(dir_arg).unwrap_or_default())
+            .map(|(path, index)| (index, SubstituteSource::Directory { path: path.clone() }))
+    });
+    let process = matches
+        .get_one::<String>(process_arg)
+        .zip(matches.index_of(process_arg))
+        .map(|(prefix, index)| {
+            let source = SubstituteSource::Process {
+                prefix: prefix.clone(),
+            };
+            (index, source)
+        });
+
+    let mut sources: Vec<_> = files.chain(dirs).chain(process).collect();
+    sources.sort_by_key(|(index, _)| *index);
+    sources.into_iter().map(|(_, source)| source).collect()
+}
+
+fn main() -> Result<()> {
+    let matches = cli().get_matches();
+    let options = RunOptions::from_matches(&matches)?;
+
+    // Set up simple logging to stdout, unless stdout is used for the output itself
+    let logging = tracing_subscriber::fmt()
         .with_target(false)
-        .with_timer(tracing_subscriber::fmt::time::uptime()) // Time since start
-        .init();
-
-    // Is already done by CLAP, but the type safety is nice
-    let schema_path = matches
-        .get_one::<String>(ARG_SCHEMA)
-        .ok_or_else(|| anyhow!("Schema argument is required"))?;
-    let input_path = matches
-        .get_one::<String>(ARG_INPUT)
-        .ok_or_else(|| anyhow!("Input argument is required"))?;
-    let env_vars_path = matches
-        .get_one::<String>(ARG_ENV_VARS_INPUT)
-        .map(String::as_str);
-    let env_secrets_path = matches
-        .get_one::<String>(ARG_ENV_SECRETS_INPUT)
-        .map(String::as_str);
-    let output_path = matches.get_one::<String>(ARG_OUTPUT).map(String::as_str);
-
-    if let Err(e) = run(
-        schema_path,
-        input_path,
-        env_vars_path,
-        env_secrets_path,
-        output_path,
-    ) {
+        .with_timer(tracing_subscriber::fmt::time::uptime()); // Time since start
+    if options.output_path.as_deref() == Some("-") {
+        logging.with_writer(io::stderr).init();
+    } else {
+        logging.init();
+    }
+
+    if let Err(e) = run(&options) {
         error!("{}", e);
 
         // Print the full error chain
@@ -110,260 +366,489 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-// Validate a JSON input against a specific JSON schema
-fn validate_json(schema: &Value, input: &Value) -> Result<()> {
-    let validator =
-        jsonschema::validator_for(schema).with_context(|| "Failed to compile JSON schema")?;
+// A JSON schema together with its compiled validator
+struct Schema {
+    value: Value,
+    validator: Validator,
+}
 
-    let errors: Vec<ValidationError> = validator.iter_errors(input).collect();
+// Schemas to validate documents against, a document can select its own schema through a discriminator field
+struct SchemaSelector {
+    default: Schema,
+    discriminator: Option<String>,
+    by_value: HashMap<String, Schema>,
+}
+
+impl SchemaSelector {
+    fn select(&self, document: &Value) -> &Schema {
+        let value = self
+            .discriminator
+            .as_ref()
+            .and_then(|field| document.get(field))
+            .map(|value| match value {
+                Value::String(s) => s.clone(),
+                _ => value.to_string(),
+            });
+
+        value
+            .and_then(|value| self.by_value.get(&value))
+            .unwrap_or(&self.default)
+    }
+}
+
+// Read a JSON schema file and compile it into a validator
+fn load_schema(schema_path: &str) -> Result<Schema> {
+    let schema = fs::read_to_string(schema_path)
+        .with_context(|| format!("Failed to read schema file: {schema_path}"))?;
+    let schema_format = Format::from_path(schema_path);
+    let schema: Value = format::parse(&schema, schema_format)
+        .with_context(|| format!("Schema file is not valid {schema_format}: {schema_path}"))?;
+
+    let validator = jsonschema::validator_for(&schema)
+        .with_context(|| format!("Failed to compile JSON schema: {schema_path}"))?;
+    Ok(Schema {
+        value: schema,
+        validator,
+    })
+}
 
-    if !errors.is_empty() {
-        let mut error_msg = format!("Schema validation failed with {} error(s):", errors.len());
+// Validate a JSON input against a specific JSON schema, returning a description of every error
+fn validation_errors(validator: &Validator, input: &Value, root_path: &str) -> Vec<String> {
+    let errors: Vec<ValidationError> = validator.iter_errors(input).collect();
 
-        for (i, error) in errors.iter().enumerate() {
-            // Then change your code to:
-            write!(
-                error_msg,
-                "\n  {}. Path: '{}' - {}",
-                i + 1,
-                error.instance_path,
+    errors
+        .iter()
+        .map(|error| {
+            format!(
+                "Path: '{}' - {}",
+                pointer_to_json_path(error.instance_path.as_str(), input, root_path),
                 error
             )
-            .unwrap();
-        }
+        })
+        .collect()
+}
+
+// Convert a JSON pointer (e.g. "/spec/ports/0") to the JSON path notation used in all messages (e.g. "$.spec.ports[0]")
+fn pointer_to_json_path(pointer: &str, input: &Value, root_path: &str) -> String {
+    let mut json_path = root_path.to_string();
+    let mut current = Some(input);
 
-        bail!(error_msg);
+    for segment in pointer.split('/').skip(1) {
+        let segment = segment.replace("~1", "/").replace("~0", "~");
+        if let Some(Value::Array(arr)) = current {
+            write!(json_path, "[{segment}]").unwrap();
+            current = segment.parse::<usize>().ok().and_then(|i| arr.get(i));
+        } else {
+            write!(json_path, ".{segment}").unwrap();
+            current = current.and_then(|v| v.get(&segment));
+        }
     }
 
-    Ok(())
+    json_path
 }
 
-// Helper function to parse substitutes from an optional path
-fn parse_substitutes_from_path(path: Option<&str>) -> Result<Option<HashMap<String, String>>> {
-    if let Some(path) = path {
-        let content: String =
-            fs::read_to_string(path).with_context(|| format!("Failed to read file: {path}"))?;
-        let json: Value = serde_json::from_str(&content)
-            .with_context(|| format!("Substitutes are not given as valid JSON: {path}"))?;
-
-        // Check if it's an object and get key count
-        if let Some(obj) = json.as_object() {
-            let key_count = obj.len();
-
-            // Convert to hashmap that can be used for quick lookups
-            let mut map = HashMap::new();
-            for (key, value) in obj {
-                let string_value = match value {
-                    Value::String(s) => s.clone(),
-                    _ => value.to_string().trim_matches('"').to_string(),
-                };
-                map.insert(key.to_lowercase(), string_value);
-            }
-            info!("Loaded {} substitutes from {}", key_count, path);
-            Ok(Some(map))
+// Read the contents of a substitutes file, which can also be given as "-" (stdin), "env:VARNAME"
+// (an environment variable) or "fd:N" (an open file descriptor) to avoid writing secrets to disk
+fn read_substitutes_content(path: &str) -> Result<String> {
+    if path == "-" {
+        let mut content = String::new();
+        io::stdin()
+            .read_to_string(&mut content)
+            .context("Failed to read substitutes from stdin")?;
+        Ok(content)
+    } else if let Some(name) = path.strip_prefix("env:") {
+        std::env::var(name).with_context(|| {
+            format!("Failed to read substitutes from environment variable: {name}")
+        })
+    } else if let Some(fd) = path.strip_prefix("fd:") {
+        let fd: u32 = fd
+            .parse()
+            .with_context(|| format!("Invalid file descriptor: {fd}"))?;
+        if cfg!(unix) {
+            fs::read_to_string(format!("/dev/fd/{fd}"))
+                .with_context(|| format!("Failed to read substitutes from file descriptor: {fd}"))
         } else {
-            bail!(
-                "Substitutes file must contain a JSON object, not {}",
-                match json {
-                    Value::Array(_) => "an array",
-                    Value::String(_) => "a string",
-                    Value::Number(_) => "a number",
-                    Value::Bool(_) => "a boolean",
-                    Value::Null => "null",
-                    Value::Object(_) => "an object",
-                }
-            );
+            bail!("Reading substitutes from a file descriptor is only supported on Unix")
         }
     } else {
-        info!("No substitutes were specified");
-        Ok(None)
+        fs::read_to_string(path).with_context(|| format!("Failed to read file: {path}"))
     }
 }
 
-fn substitute_values(
-    input: &mut Value,
-    env_secrets: Option<&HashMap<String, String>>,
-    env_vars: Option<&HashMap<String, String>>,
-) -> Result<()> {
-    let template_regex =
-        Regex::new(r"\{\{\s*([^}]+)\s*\}\}").context("Failed to compile template regex")?;
-
-    substitute_recursive(input, env_secrets, env_vars, &template_regex, "$")?;
-    Ok(())
+// Helper function to parse substitutes from a path
+fn parse_substitutes_from_path(
+    path: &str,
+    format: Option<Format>,
+) -> Result<HashMap<String, Value>> {
+    let content = read_substitutes_content(path)?;
+    let format = format.unwrap_or_else(|| Format::from_path(path));
+    let json: Value = format::parse(&content, format)
+        .with_context(|| format!("Substitutes are not given as valid {format}: {path}"))?;
+
+    // Check if it's an object and get key count
+    if let Some(obj) = json.as_object() {
+        let key_count = obj.len();
+
+        // Convert to hashmap that can be used for quick lookups, values keep their structure so that
+        // placeholders can point into them (e.g. {{ db.ports[0] }})
+        let map = obj
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        info!("Loaded {} substitutes from {}", key_count, path);
+        Ok(map)
+    } else {
+        bail!(
+            "Substitutes file must contain an object, not {}",
+            match json {
+                Value::Array(_) => "an array",
+                Value::String(_) => "a string",
+                Value::Number(_) => "a number",
+                Value::Bool(_) => "a boolean",
+                Value::Null => "null",
+                Value::Object(_) => "an object",
+            }
+        );
+    }
 }
 
-fn substitute_recursive(
-    value: &mut Value,
-    env_secrets: Option<&HashMap<String, String>>,
-    env_vars: Option<&HashMap<String, String>>,
-    regex: &Regex,
-    json_path: &str,
-) -> Result<()> {
-    match value {
-        Value::String(s) => {
-            let original = s.clone();
-            *s = substitute_string(s, env_secrets, env_vars, regex, json_path)?;
-
-            // Log if substitution occurred
-            if *s != original {
-                debug!(
-                    "Substituted value at path '{}': '{}' -> '{}'",
-                    json_path, original, s
-                );
-            }
-        }
-        Value::Object(obj) => {
-            for (key, v) in obj.iter_mut() {
-                let new_path = if json_path == "$" {
-                    format!("$.{key}")
-                } else {
-                    format!("{json_path}.{key}")
-                };
-                substitute_recursive(v, env_secrets, env_vars, regex, &new_path)?;
-            }
-        }
-        Value::Array(arr) => {
-            for (index, item) in arr.iter_mut().enumerate() {
-                let new_path = format!("{json_path}[{index}]");
-                substitute_recursive(item, env_secrets, env_vars, regex, &new_path)?;
-            }
+// Helper function to parse substitutes from a directory with one file per key, as secrets are
+// mounted by Kubernetes and Docker Swarm
+fn parse_substitutes_from_dir(path: &str) -> Result<HashMap<String, Value>> {
+    let mut map = HashMap::new();
+
+    for entry in
+        fs::read_dir(path).with_context(|| format!("Failed to read secrets directory: {path}"))?
+    {
+        let entry = entry.with_context(|| format!("Failed to read entry in directory: {path}"))?;
+        let file_path = entry.path();
+
+        // Kubernetes keeps its bookkeeping in "..data" style entries, which are not secrets
+        let Some(key) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        if key.starts_with("..") || !file_path.is_file() {
+            continue;
         }
-        _ => {} // Numbers, booleans, null don't need substitution
+
+        let content = fs::read_to_string(&file_path)
+            .with_context(|| format!("Failed to read file: {}", file_path.display()))?;
+        let value = content
+            .strip_suffix('\n')
+            .map_or(content.as_str(), |c| c.strip_suffix('\r').unwrap_or(c));
+        map.insert(key, Value::String(value.to_string()));
     }
-    Ok(())
+
+    info!("Loaded {} substitutes from directory {}", map.len(), path);
+    Ok(map)
 }
 
-fn substitute_string(
-    s: &str,
-    env_secrets: Option<&HashMap<String, String>>,
-    env_vars: Option<&HashMap<String, String>>,
-    regex: &Regex,
-    json_path: &str,
-) -> Result<String> {
-    let mut result = s.to_string();
-
-    for cap in regex.captures_iter(s) {
-        let full_match = &cap[0]; // The entire {{ NAME }} part
-        let var_name = cap[1].trim();
-        let var_name_lower = var_name.to_lowercase();
-
-        // Try env_secrets first, then env_vars
-        let (replacement, source) = if let Some(secrets) = env_secrets {
-            if let Some(value) = secrets.get(&var_name_lower) {
-                (value.clone(), "env_secrets")
-            } else if let Some(vars) = env_vars {
-                if let Some(value) = vars.get(&var_name_lower) {
-                    (value.clone(), "env_vars")
-                } else {
-                    bail!(
-                        "Substitution variable '{}' specified at path '{}', but its value was not found in env_secrets or env_vars",
-                        var_name,
-                        json_path
+// Helper function to collect substitutes from the environment of this process, only variables
+// that start with the prefix are used and the prefix is stripped (e.g. APP_DB_HOST becomes db_host)
+fn parse_substitutes_from_process(prefix: &str) -> HashMap<String, Value> {
+    // Variables that are not valid unicode cannot be substituted into JSON anyway
+    let map: HashMap<String, Value> = std::env::vars_os()
+        .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)))
+        .filter_map(|(key, value)| {
+            let key = key.strip_prefix(prefix)?;
+            (!key.is_empty()).then(|| (key.to_string(), Value::String(value)))
+        })
+        .collect();
+
+    if prefix.is_empty() {
+        info!(
+            "Loaded {} substitutes from the process environment",
+            map.len()
+        );
+    } else {
+        info!(
+            "Loaded {} substitutes from the process environment with prefix {}",
+            map.len(),
+            prefix
+        );
+    }
+    map
+}
+
+// Combine substitutes from multiple sources, later sources take precedence over earlier ones. Unless
+// keys are case-sensitive they are lowercased, keys of one source that only differ in case are an error.
+fn load_substitutes(
+    sources: &[SubstituteSource],
+    case_sensitive: bool,
+) -> Result<Option<HashMap<String, Value>>> {
+    if sources.is_empty() {
+        info!("No substitutes were specified");
+        return Ok(None);
+    }
+
+    let mut merged = HashMap::new();
+    let mut origins = HashMap::new();
+    for source in sources {
+        let map = match source {
+            SubstituteSource::File { path, format } => parse_substitutes_from_path(path, *format)?,
+            SubstituteSource::Directory { path } => parse_substitutes_from_dir(path)?,
+            SubstituteSource::Process { prefix } => parse_substitutes_from_process(prefix),
+        };
+
+        // Keys that only differ in case are fine as long as their values are the same. The process environment
+        // is not under the user's control (e.g. both http_proxy and HTTP_PROXY on CI runners), so there an
+        // ambiguous key is left out with a warning instead of failing.
+        let mut keys: HashMap<String, (String, Value)> = HashMap::new();
+        let mut ambiguous = Vec::new();
+        for (original_key, value) in map {
+            let key = if case_sensitive {
+                original_key.clone()
+            } else {
+                original_key.to_lowercase()
+            };
+            match keys.get(&key) {
+                Some((_, other_value)) if *other_value == value => {}
+                Some((other_key, _)) => {
+                    let (first, second) = if *other_key < original_key {
+                        (other_key.clone(), original_key)
+                    } else {
+                        (original_key, other_key.clone())
+                    };
+                    if !matches!(source, SubstituteSource::Process { .. }) {
+                        bail!(
+                            "Substitutes '{first}' and '{second}' in {source} only differ in case, use --case-sensitive to keep them apart"
+                        );
+                    }
+                    warn!(
+                        "Substitutes '{first}' and '{second}' in {source} only differ in case and have different values, so neither is used (use --case-sensitive to keep them apart)"
                     );
+                    ambiguous.push(key);
+                }
+                None => {
+                    keys.insert(key, (original_key, value));
                 }
-            } else {
-                bail!(
-                    "Substitution variable '{}' specified at path '{}', but its value was not found in env_secrets, and no env_vars was specified",
-                    var_name,
-                    json_path
-                );
-            }
-        } else if let Some(vars) = env_vars {
-            if let Some(value) = vars.get(&var_name_lower) {
-                (value.clone(), "env_vars")
-            } else {
-                bail!(
-                    "Substitution variable '{}' specified at path '{}', but its value was not found in env_vars, and no env_secrets was specified",
-                    var_name,
-                    json_path
-                );
             }
-        } else {
-            bail!(
-                "Substitution variable '{}' specified at path '{}', but no substitution sources (env_vars, env_secrets) were provided",
-                var_name,
-                json_path
-            );
-        };
+        }
 
-        result = result.replace(full_match, &replacement);
-        debug!(
-            "Replaced '{{{{ {} }}}}' with value from {} at JSON path '{}'",
-            var_name, source, json_path
-        );
+        for key in ambiguous {
+            keys.remove(&key);
+        }
+        for (key, (_, value)) in keys {
+            origins.insert(key.clone(), source);
+            merged.insert(key, value);
+        }
+    }
+
+    // Only log where keys came from, values can be secret
+    let mut keys: Vec<_> = origins.into_iter().collect();
+    keys.sort_by(|a, b| a.0.cmp(&b.0));
+    for (key, source) in keys {
+        debug!("Substitute '{}' is taken from {}", key, source);
     }
 
-    Ok(result)
+    Ok(Some(merged))
 }
 
-fn run(
-    schema_path: &str,
-    input_path: &str,
-    env_vars_path: Option<&str>,
-    env_secrets_path: Option<&str>,
-    output_path: Option<&str>,
-) -> Result<()> {
-    // Read actual files
-    let schema = fs::read_to_string(schema_path)
-        .with_context(|| format!("Failed to read schema file: {schema_path}"))?;
-    let input = fs::read_to_string(input_path)
-        .with_context(|| format!("Failed to read input file: {input_path}"))?;
+// The canonical directory that `file:` placeholders are relative to, which is the directory of the input file (or
+// the working directory for stdin), and the canonical file root they cannot reach outside of
+fn file_dirs(input_path: &str, file_root: Option<&str>) -> Result<(PathBuf, PathBuf)> {
+    let file_dir = Path::new(input_path)
+        .parent()
+        .filter(|dir| input_path != "-" && !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let file_root = file_root.map_or(file_dir, Path::new);
+    let canonical_root = file_root.canonicalize().with_context(|| {
+        format!(
+            "File root is not an existing directory: {}",
+            file_root.display()
+        )
+    })?;
+    let canonical_dir = file_dir
+        .canonicalize()
+        .with_context(|| format!("Failed to read directory: {}", file_dir.display()))?;
+    Ok((canonical_dir, canonical_root))
+}
+
+fn run(options: &RunOptions) -> Result<()> {
+    let input_path = options.input_path.as_str();
+    let output_path = options.output_path.as_deref();
+
+    // Stdin can only be read once
+    let stdin_sources = options
+        .env_vars_sources
+        .iter()
+        .chain(&options.env_secrets_sources)
+        .filter(|source| matches!(source, SubstituteSource::File { path, .. } if path == "-"))
+        .count()
+        + usize::from(input_path == "-");
+    if stdin_sources > 1 {
+        bail!("Stdin ('-') can only be used for one input");
+    }
+
+    // Read actual files, "-" reads the input from stdin
+    let input = if input_path == "-" {
+        let mut input = String::new();
+        io::stdin()
+            .read_to_string(&mut input)
+            .context("Failed to read input from stdin")?;
+        input
+    } else {
+        fs::read_to_string(input_path)
+            .with_context(|| format!("Failed to read input file: {input_path}"))?
+    };
 
     // Error if the output path already exists
-    if let Some(output_path) = output_path {
-        if fs::metadata(output_path).is_ok() {
-            bail!("Output file '{output_path}' already exists. Will not overwrite");
-        }
+    if let Some(output_path) = output_path
+        && output_path != "-"
+        && fs::metadata(output_path).is_ok()
+    {
+        bail!("Output file '{output_path}' already exists. Will not overwrite");
     }
 
     // Substitutes can be used to produce the final JSON output later (this is the JSON that gets validated)
     info!("Parsing environment variable substitutes");
-    let env_vars = parse_substitutes_from_path(env_vars_path)?;
+    let env_vars = load_substitutes(&options.env_vars_sources, options.case_sensitive)?;
     info!("Parsing environment secret substitutes");
-    let env_secrets = parse_substitutes_from_path(env_secrets_path)?;
-
-    // Convert to JSON
-    let schema: Value = serde_json::from_str(&schema)
-        .with_context(|| format!("Schema file is not valid JSON: {schema_path}"))?;
-    let mut input: Value = serde_json::from_str(&input)
-        .with_context(|| format!("Input file is not valid JSON: {input_path}"))?;
-
-    // Convert &option<hashmasp> to option<&hashmap>
-    let env_vars = env_vars.as_ref().map(|m| m as &HashMap<String, String>);
-    let env_secrets = env_secrets.as_ref().map(|m| m as &HashMap<String, String>);
-
-    info!("Scanning for substitution placeholders");
-    substitute_values(&mut input, env_secrets, env_vars)?;
-    info!("Substitutions succeeded, performing schema validation");
-    validate_json(&schema, &input)?;
+    let env_secrets = load_substitutes(&options.env_secrets_sources, options.case_sensitive)?;
+
+    // Compile all schemas up front, so that every document is validated against an already compiled schema
+    let mut schemas = SchemaSelector {
+        default: load_schema(&options.schema_path)?,
+        discriminator: options.discriminator.clone(),
+        by_value: HashMap::new(),
+    };
+    for (value, schema_path) in &options.document_schema_paths {
+        schemas
+            .by_value
+            .insert(value.clone(), load_schema(schema_path)?);
+    }
+
+    // Convert to JSON, the input format can be overridden because its file name is not always telling
+    let input_format = options
+        .input_format
+        .unwrap_or_else(|| Format::from_path(input_path));
+    let mut documents = format::parse_documents(&input, input_format)
+        .with_context(|| format!("Input file is not valid {input_format}: {input_path}"))?;
+    info!("Loaded {} document(s) from {}", documents.len(), input_path);
+
+    let (file_dir, file_root) = file_dirs(input_path, options.file_root.as_deref())?;
+    let substitutes = Substitutes {
+        env_secrets: env_secrets.as_ref(),
+        env_vars: env_vars.as_ref(),
+        case_sensitive: options.case_sensitive,
+        max_expansion_depth: options.max_expansion_depth,
+        placeholder_syntax: options.placeholder_syntax.clone(),
+        git: Git::new(file_dir.clone()),
+        file_dir,
+        file_root,
+    };
+
+    // Every document is substituted and validated on its own, but errors are reported for all documents at once
+    let multi_document = documents.len() > 1;
+    let mut failures = Vec::new();
+    let mut errors = Vec::new();
+    for (index, document) in documents.iter_mut().enumerate() {
+        let root_path = if multi_document {
+            format!("doc[{index}].$")
+        } else {
+            "$".to_string()
+        };
+
+        // The schema is selected before substitution, so that it can tell which types placeholders should produce
+        let schema = schemas.select(document);
+
+        info!("Scanning for substitution placeholders in {root_path}");
+        // A document that failed substitution is not validated, its placeholders would only add more errors
+        if let Err(e) = substitute::substitute_values(
+            document,
+            &substitutes,
+            &SchemaCursor::new(&schema.value),
+            &root_path,
+        ) {
+            failures.push(e);
+            continue;
+        }
+        info!("Substitutions succeeded, performing schema validation of {root_path}");
+        errors.extend(validation_errors(&schema.validator, document, &root_path));
+    }
+
+    report_errors(failures, &errors)?;
     info!("Validation successful");
 
     // Write to output file if specified
     if let Some(output_path) = output_path {
-        info!("Writing validated JSON to output file: {output_path}");
-
-        let pretty_json =
-            serde_json::to_string_pretty(&input).context("Failed to serialize JSON for output")?;
+        // Stdout has no file extension, so it keeps the input format unless told otherwise
+        let output_format = options
+            .output_format
+            .or_else(|| (output_path == "-").then_some(input_format));
+        write_output(&documents, output_path, output_format)?;
+    } else {
+        info!("No output file specified, done");
+    }
 
-        let len = pretty_json.len();
-        fs::write(output_path, pretty_json)
-            .with_context(|| format!("Failed to write output file: {output_path}"))?;
+    Ok(())
+}
 
-        info!(
-            "Successfully wrote output JSON ({} bytes) to {}",
-            len, output_path
+// Fail with the substitution errors and the schema validation errors of all documents, if there are any
+fn report_errors(mut failures: Vec<anyhow::Error>, validation_errors: &[String]) -> Result<()> {
+    if !validation_errors.is_empty() {
+        let mut error_msg = format!(
+            "Schema validation failed with {} error(s):",
+            validation_errors.len()
         );
+        for (i, error) in validation_errors.iter().enumerate() {
+            write!(error_msg, "\n  {}. {}", i + 1, error).unwrap();
+        }
+        failures.push(anyhow!(error_msg));
+    }
+
+    // A single error keeps its context, several are joined into one message
+    match failures.len() {
+        0 => Ok(()),
+        1 => Err(failures.remove(0)),
+        _ => {
+            let messages: Vec<String> = failures.iter().map(|e| format!("{e:#}")).collect();
+            bail!(messages.join("\n"))
+        }
+    }
+}
+
+// Write the validated documents to a file, or to stdout if the path is "-"
+fn write_output(
+    documents: &[Value],
+    output_path: &str,
+    output_format: Option<Format>,
+) -> Result<()> {
+    let output_format = output_format.unwrap_or_else(|| Format::from_path(output_path));
+    info!("Writing validated {output_format} to output file: {output_path}");
+
+    let mut output = format::to_string(documents, output_format)
+        .with_context(|| format!("Failed to serialize {output_format} for output"))?;
+
+    let len = output.len();
+    if output_path == "-" {
+        // End with a newline, so that the next shell prompt or document starts on its own line
+        if !output.ends_with('\n') {
+            output.push('\n');
+        }
+        let mut stdout = io::stdout().lock();
+        stdout
+            .write_all(output.as_bytes())
+            .and_then(|()| stdout.flush())
+            .context("Failed to write output to stdout")?;
     } else {
-        info!("No output file specified, done");
+        fs::write(output_path, output)
+            .with_context(|| format!("Failed to write output file: {output_path}"))?;
     }
 
+    info!(
+        "Successfully wrote output {} ({} bytes) to {}",
+        output_format, len, output_path
+    );
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use serde_json::Value;
+    use flate2::Compression;
+    use flate2::write::ZlibEncoder;
+    use sha1::{Digest, Sha1};
     use std::fs;
     use std::path::{Path, PathBuf};
     use tempfile::TempDir;
@@ -373,9 +858,11 @@ mod tests {
         name: String,
         input_path: PathBuf,
         schema_path: PathBuf,
-        expected_output_path: PathBuf,
+        expected_output_path: Option<PathBuf>,
+        expected_error: Option<String>,
         env_vars_path: Option<PathBuf>,
         env_secrets_path: Option<PathBuf>,
+        extra_args: Vec<String>,
     }
 
     impl TestCase {
@@ -386,47 +873,62 @@ mod tests {
                 .ok_or_else(|| anyhow!("Invalid directory name: {:?}", dir))?
                 .to_string();
 
-            // Check for required files
-            let input_path = dir.join("input.json");
-            let schema_path = dir.join("schema.json");
-            let expected_output_path = dir.join("expected-output.json");
-
-            if !input_path.exists() {
-                bail!("Missing required file in test '{}': input.json", name);
-            }
-            if !schema_path.exists() {
-                bail!("Missing required file in test '{}': schema.json", name);
-            }
-            if !expected_output_path.exists() {
+            // Check for required files, input and schema can be given in any supported format
+            let Some(input_path) = find_file(dir, "input") else {
+                bail!("Missing required file in test '{}': input.*", name);
+            };
+            let Some(schema_path) = find_file(dir, "schema") else {
+                bail!("Missing required file in test '{}': schema.*", name);
+            };
+            // A test either expects an output, or an error that contains the given text
+            let expected_output_path = find_file(dir, "expected-output");
+            let expected_error_path = dir.join("expected-error.txt");
+            let expected_error = if expected_error_path.exists() {
+                Some(fs::read_to_string(&expected_error_path)?.trim().to_string())
+            } else {
+                None
+            };
+            if expected_output_path.is_none() && expected_error.is_none() {
                 bail!(
-                    "Missing required file in test '{}': expected-output.json",
+                    "Missing required file in test '{}': expected-output.* or expected-error.txt",
                     name
                 );
             }
 
-            // Check for optional files
-            let env_vars_path = dir.join("env-vars.json");
-            let env_secrets_path = dir.join("env-secrets.json");
+            // Additional command line arguments can be given one per line
+            let args_path = dir.join("args.txt");
+            let extra_args = if args_path.exists() {
+                fs::read_to_string(&args_path)?
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .map(String::from)
+                    .collect()
+            } else {
+                Vec::new()
+            };
 
             Ok(TestCase {
                 name,
                 input_path,
                 schema_path,
                 expected_output_path,
-                env_vars_path: if env_vars_path.exists() {
-                    Some(env_vars_path)
-                } else {
-                    None
-                },
-                env_secrets_path: if env_secrets_path.exists() {
-                    Some(env_secrets_path)
-                } else {
-                    None
-                },
+                expected_error,
+                env_vars_path: find_file(dir, "env-vars"),
+                env_secrets_path: find_file(dir, "env-secrets"),
+                extra_args,
             })
         }
     }
 
+    // Find a file by its name without extension (e.g. "input" matches "input.yaml")
+    fn find_file(dir: &Path, stem: &str) -> Option<PathBuf> {
+        fs::read_dir(dir)
+            .ok()?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .find(|path| path.is_file() && path.file_stem().and_then(|s| s.to_str()) == Some(stem))
+    }
+
     fn discover_test_cases() -> Result<Vec<TestCase>> {
         let tests_dir = Path::new("tests");
 
@@ -476,49 +978,94 @@ mod tests {
         // Create a temporary directory for output
         let temp_dir = TempDir::new()
             .with_context(|| format!("Failed to create temp dir for test '{}'", test_case.name))?;
-        let actual_output_path = temp_dir.path().join("actual_output.json");
 
-        // Run the function
-        run(
-            test_case.schema_path.to_str().unwrap(),
-            test_case.input_path.to_str().unwrap(),
-            test_case
-                .env_vars_path
-                .as_ref()
-                .map(|p| p.to_str().unwrap()),
+        // Write the output in the same format as the expected output
+        let actual_output_path = temp_dir.path().join(format!(
+            "actual_output.{}",
             test_case
-                .env_secrets_path
+                .expected_output_path
                 .as_ref()
-                .map(|p| p.to_str().unwrap()),
-            Some(actual_output_path.to_str().unwrap()),
-        )
-        .with_context(|| format!("Test case '{}' failed during execution", test_case.name))?;
+                .and_then(|p| p.extension())
+                .and_then(|e| e.to_str())
+                .unwrap_or("json")
+        ));
+
+        // Run through the command line parser, so that extra arguments are handled like in main()
+        let mut args = vec![
+            NAME.to_string(),
+            "--schema".to_string(),
+            test_case.schema_path.display().to_string(),
+            "--input".to_string(),
+            test_case.input_path.display().to_string(),
+            "--output".to_string(),
+            actual_output_path.display().to_string(),
+        ];
+        if let Some(path) = &test_case.env_vars_path {
+            args.extend(["--env-vars".to_string(), path.display().to_string()]);
+        }
+        if let Some(path) = &test_case.env_secrets_path {
+            args.extend(["--env-secrets".to_string(), path.display().to_string()]);
+        }
+        args.extend(test_case.extra_args.iter().cloned());
+
+        let matches = cli()
+            .try_get_matches_from(args)
+            .with_context(|| format!("Invalid arguments for test '{}'", test_case.name))?;
+        let options = RunOptions::from_matches(&matches)?;
+
+        // Run the function
+        let result = run(&options);
+
+        if let Some(expected_error) = &test_case.expected_error {
+            match result {
+                Ok(()) => bail!(
+                    "Test case '{}' succeeded, but was expected to fail with: {}",
+                    test_case.name,
+                    expected_error
+                ),
+                Err(e) if !format!("{e:#}").contains(expected_error.as_str()) => bail!(
+                    "Test case '{}' failed with an unexpected error\nExpected:\n{}\nActual:\n{:#}",
+                    test_case.name,
+                    expected_error,
+                    e
+                ),
+                Err(_) => {
+                    println!("✓ Test case '{}' passed", test_case.name);
+                    return Ok(());
+                }
+            }
+        }
+        result
+            .with_context(|| format!("Test case '{}' failed during execution", test_case.name))?;
 
         // Read and parse the expected output
-        let expected_content =
-            fs::read_to_string(&test_case.expected_output_path).with_context(|| {
-                format!(
-                    "Failed to read expected output for test '{}'",
-                    test_case.name
-                )
-            })?;
-        let expected_json: Value = serde_json::from_str(&expected_content).with_context(|| {
+        let expected_output_path = test_case.expected_output_path.as_ref().unwrap();
+        let expected_format = Format::from_path(expected_output_path.to_str().unwrap());
+        let expected_content = fs::read_to_string(expected_output_path).with_context(|| {
             format!(
-                "Expected output is not valid JSON for test '{}'",
+                "Failed to read expected output for test '{}'",
                 test_case.name
             )
         })?;
+        let expected_json = format::parse_documents(&expected_content, expected_format)
+            .with_context(|| {
+                format!(
+                    "Expected output is not valid {} for test '{}'",
+                    expected_format, test_case.name
+                )
+            })?;
 
         // Read and parse the actual output
         let actual_content = fs::read_to_string(&actual_output_path).with_context(|| {
             format!("Failed to read actual output for test '{}'", test_case.name)
         })?;
-        let actual_json: Value = serde_json::from_str(&actual_content).with_context(|| {
-            format!(
-                "Actual output is not valid JSON for test '{}'",
-                test_case.name
-            )
-        })?;
+        let actual_json =
+            format::parse_documents(&actual_content, expected_format).with_context(|| {
+                format!(
+                    "Actual output is not valid {} for test '{}'",
+                    expected_format, test_case.name
+                )
+            })?;
 
         // Compare JSON values
         if expected_json != actual_json {
@@ -591,4 +1138,103 @@ mod tests {
 
         Ok(())
     }
+
+    // Git refuses to track a `.git` directory, so this repository is built in a temporary directory instead of
+    // being a test case under tests/
+    #[test]
+    fn test_git_metadata() -> Result<()> {
+        let sha = "3e0024d9b934bbc5189b1ad38c367bcb10b2bfaf";
+        let dir = TempDir::new()?;
+        let git_dir = dir.path().join(".git");
+        fs::create_dir_all(git_dir.join("refs/heads/release"))?;
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/release/1.x\n")?;
+        fs::write(git_dir.join("refs/heads/release/1.x"), format!("{sha}\n"))?;
+        fs::write(
+            git_dir.join("packed-refs"),
+            format!(
+                "# pack-refs with: peeled fully-peeled sorted\n\
+                 {sha} refs/tags/v1.9.0\n\
+                 8a1f3c1b2b9e4f6f0c7d1e2a3b4c5d6e7f8a9b0c refs/tags/v1.10.0\n\
+                 ^{sha}\n\
+                 0f1e2d3c4b5a69788796a5b4c3d2e1f0a9b8c7d6 refs/tags/v2.0.0\n"
+            ),
+        )?;
+
+        // An index with a single file, whose modification time does not match so that its contents are compared
+        let contents = "hello\n";
+        let blob = Sha1::new()
+            .chain_update(format!("blob {}\0{contents}", contents.len()))
+            .finalize();
+        let mut index = b"DIRC\0\0\0\x02\0\0\0\x01".to_vec();
+        index.extend([0; 24]);
+        index.extend(0o100_644_u32.to_be_bytes());
+        index.extend([0; 8]);
+        index.extend(u32::try_from(contents.len())?.to_be_bytes());
+        index.extend(blob);
+        index.extend(7_u16.to_be_bytes());
+        index.extend(b"app.txt\0\0\0");
+        fs::write(git_dir.join("index"), index)?;
+        fs::write(dir.path().join("app.txt"), contents)?;
+
+        let schema_path = dir.path().join("schema.json");
+        let input_path = dir.path().join("input.json");
+        fs::write(
+            &schema_path,
+            r#"{ "type": "object", "properties": { "dirty": { "type": "boolean" } } }"#,
+        )?;
+        fs::write(
+            &input_path,
+            r#"{
+                "sha": "{{ git.sha }}",
+                "version": "{{ git.tag }}-{{ git.short_sha }}",
+                "branch": "{{ git.branch }}",
+                "dirty": "{{ git.dirty }}"
+            }"#,
+        )?;
+
+        let run_with_output = |name: &str| -> Result<Value> {
+            let output_path = dir.path().join(name);
+            let matches = cli().try_get_matches_from([
+                NAME,
+                "--schema",
+                schema_path.to_str().unwrap(),
+                "--input",
+                input_path.to_str().unwrap(),
+                "--output",
+                output_path.to_str().unwrap(),
+            ])?;
+            run(&RunOptions::from_matches(&matches)?)?;
+            Ok(serde_json::from_str(&fs::read_to_string(output_path)?)?)
+        };
+
+        assert_eq!(
+            run_with_output("clean.json")?,
+            serde_json::json!({
+                "sha": sha,
+                "version": "v1.10.0-3e0024d",
+                "branch": "release/1.x",
+                "dirty": false
+            })
+        );
+
+        fs::write(dir.path().join("app.txt"), "changed\n")?;
+        assert_eq!(run_with_output("dirty.json")?["dirty"], Value::Bool(true));
+
+        // A loose annotated tag made after `git gc`, whose tag object is loose but the commit it points to packed
+        let tag = format!("object {sha}\ntype commit\ntag v1.11.0\n\nRelease\n");
+        let tag = format!("tag {}\0{tag}", tag.len());
+        let tag_sha = format!("{:x}", Sha1::digest(&tag));
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(tag.as_bytes())?;
+        let object_dir = git_dir.join("objects").join(&tag_sha[..2]);
+        fs::create_dir_all(&object_dir)?;
+        fs::write(object_dir.join(&tag_sha[2..]), encoder.finish()?)?;
+        fs::create_dir_all(git_dir.join("refs/tags"))?;
+        fs::write(git_dir.join("refs/tags/v1.11.0"), format!("{tag_sha}\n"))?;
+        assert_eq!(
+            run_with_output("annotated.json")?["version"],
+            Value::String("v1.11.0-3e0024d".to_string())
+        );
+        Ok(())
+    }
 }
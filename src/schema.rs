@@ -0,0 +1,137 @@
+use regex::Regex;
+use serde_json::Value;
+
+// References can point to each other, so stop following them at some point
+const MAX_REF_DEPTH: usize = 32;
+
+// Walks a JSON schema alongside a document, to find out which types are expected at each location.
+// Subschemas of allOf, anyOf and oneOf are all followed, as any of them can apply to the location.
+#[derive(Debug, Clone)]
+pub struct SchemaCursor<'a> {
+    root: &'a Value,
+    candidates: Vec<&'a Value>,
+}
+
+impl<'a> SchemaCursor<'a> {
+    pub fn new(root: &'a Value) -> Self {
+        let mut cursor = SchemaCursor {
+            root,
+            candidates: Vec::new(),
+        };
+        cursor.expand(root, 0);
+        cursor
+    }
+
+    // Move the cursor to the schema of a property of an object
+    pub fn property(&self, key: &str) -> Self {
+        let mut cursor = self.child();
+
+        for schema in &self.candidates {
+            if let Some(property) = schema.get("properties").and_then(|p| p.get(key)) {
+                cursor.expand(property, 0);
+                continue;
+            }
+
+            let mut matched = false;
+            if let Some(patterns) = schema.get("patternProperties").and_then(Value::as_object) {
+                for (pattern, property) in patterns {
+                    if Regex::new(pattern).is_ok_and(|r| r.is_match(key)) {
+                        cursor.expand(property, 0);
+                        matched = true;
+                    }
+                }
+            }
+
+            if !matched && let Some(additional) = schema.get("additionalProperties") {
+                cursor.expand(additional, 0);
+            }
+        }
+
+        cursor
+    }
+
+    // Move the cursor to the schema of an item of an array
+    pub fn item(&self, index: usize) -> Self {
+        let mut cursor = self.child();
+
+        for schema in &self.candidates {
+            // Tuples are described by "prefixItems" (2020-12) or an array of "items" (earlier drafts)
+            let tuple = schema
+                .get("prefixItems")
+                .or_else(|| schema.get("items").filter(|items| items.is_array()))
+                .and_then(Value::as_array);
+
+            if let Some(item) = tuple.and_then(|items| items.get(index)) {
+                cursor.expand(item, 0);
+            } else if tuple.is_some() {
+                let rest = schema
+                    .get("additionalItems")
+                    .or_else(|| schema.get("items").filter(|items| items.is_object()));
+                if let Some(item) = rest {
+                    cursor.expand(item, 0);
+                }
+            } else if let Some(item) = schema.get("items") {
+                cursor.expand(item, 0);
+            }
+        }
+
+        cursor
+    }
+
+    // All types (e.g. "integer" or "string") the schema allows at this location, in schema order
+    pub fn types(&self) -> Vec<&'a str> {
+        let mut types = Vec::new();
+
+        for schema in &self.candidates {
+            let found: Vec<&str> = match schema.get("type") {
+                Some(Value::String(t)) => vec![t.as_str()],
+                Some(Value::Array(ts)) => ts.iter().filter_map(Value::as_str).collect(),
+                _ => Vec::new(),
+            };
+            for t in found {
+                if !types.contains(&t) {
+                    types.push(t);
+                }
+            }
+        }
+
+        types
+    }
+
+    fn child(&self) -> Self {
+        SchemaCursor {
+            root: self.root,
+            candidates: Vec::new(),
+        }
+    }
+
+    // Add a schema as candidate, following its references and combinators
+    fn expand(&mut self, schema: &'a Value, depth: usize) {
+        if depth > MAX_REF_DEPTH || !schema.is_object() {
+            return;
+        }
+        self.candidates.push(schema);
+
+        if let Some(target) = schema
+            .get("$ref")
+            .and_then(Value::as_str)
+            .and_then(|reference| self.resolve_ref(reference))
+        {
+            self.expand(target, depth + 1);
+        }
+
+        for combinator in ["allOf", "anyOf", "oneOf"] {
+            if let Some(subschemas) = schema.get(combinator).and_then(Value::as_array) {
+                for subschema in subschemas {
+                    self.expand(subschema, depth + 1);
+                }
+            }
+        }
+    }
+
+    // Only references within the same schema (e.g. "#/definitions/port") can be followed
+    fn resolve_ref(&self, reference: &str) -> Option<&'a Value> {
+        let pointer = reference.strip_prefix('#')?;
+        self.root.pointer(pointer)
+    }
+}
@@ -0,0 +1,853 @@
+use crate::git::Git;
+use crate::schema::SchemaCursor;
+use crate::template::{self, Expression, Filter, Key, Segment, Syntax, Term, Variable};
+use anyhow::{Context, Result, anyhow, bail};
+use serde_json::{Map, Number, Value};
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use tracing::{debug, warn};
+
+// The substitutes that placeholders are resolved from
+pub struct Substitutes<'a> {
+    pub env_secrets: Option<&'a HashMap<String, Value>>,
+    pub env_vars: Option<&'a HashMap<String, Value>>,
+    // Top-level keys of the substitutes are only lowercased when lookups are case-insensitive
+    pub case_sensitive: bool,
+    // Placeholders in substitute values are only expanded when enabled, up to this many levels deep
+    pub max_expansion_depth: Option<usize>,
+    // The delimiters that mark placeholders, in the input document as well as in expanded substitute values
+    pub placeholder_syntax: Syntax,
+    // `file:` placeholders are relative to the directory of the input, and cannot reach outside the file root (both
+    // canonical)
+    pub file_dir: PathBuf,
+    pub file_root: PathBuf,
+    // Metadata of the git repository that contains the input, for `git.` placeholders
+    pub git: Git,
+}
+
+// The document that is being substituted, so that placeholders can refer to other locations in it (e.g.
+// `{{ $.api.base_url }}`). Referenced values are substituted when they are first needed.
+struct Document<'a> {
+    // The document as it was before substitution
+    original: Value,
+    schema: SchemaCursor<'a>,
+    root_path: String,
+    // Substituted string values by JSON pointer, None when the value is removed. JSON paths can't be used for this,
+    // as `$.a.b` is both the key `a.b` and the key `b` inside `a`.
+    resolved: HashMap<String, Option<Value>>,
+    // JSON pointers and paths of the values that are being substituted, to detect reference cycles
+    resolving: Vec<(String, String)>,
+    // Names of the substitution variables whose values are being expanded, to detect cycles
+    expanding: Vec<String>,
+    // Placeholders that could not be resolved, reported together once the whole document is done
+    unresolved: Vec<Unresolved>,
+}
+
+struct Unresolved {
+    // The variable names of the expression, e.g. `foo ?? bar`
+    var_name: String,
+    // Where the variables were looked for, and sources that would have been searched if specified
+    searched: Vec<&'static str>,
+    missing: Vec<&'static str>,
+    json_paths: Vec<String>,
+}
+
+impl fmt::Display for Unresolved {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json_paths = self
+            .json_paths
+            .iter()
+            .map(|json_path| format!("'{json_path}'"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(f, "'{}' at path(s) {json_paths}", self.var_name)?;
+
+        match (self.searched.as_slice(), self.missing.as_slice()) {
+            (searched, []) => write!(f, " was not found in {}", searched.join(" or ")),
+            ([], missing) => write!(
+                f,
+                " cannot be resolved, as no {} was specified",
+                missing.join(" or ")
+            ),
+            (searched, missing) => write!(
+                f,
+                " was not found in {}, and no {} was specified",
+                searched.join(" or "),
+                missing.join(" or ")
+            ),
+        }
+    }
+}
+
+impl Document<'_> {
+    fn enter(&mut self, pointer: &str, json_path: &str) -> Result<()> {
+        if let Some(start) = self
+            .resolving
+            .iter()
+            .position(|(other, _)| other == pointer)
+        {
+            let json_paths: Vec<String> = self.resolving[start..]
+                .iter()
+                .map(|(_, json_path)| json_path.clone())
+                .collect();
+            bail!(
+                "Values refer to each other in a cycle: {}",
+                chain(&json_paths, json_path)
+            );
+        }
+        self.resolving
+            .push((pointer.to_string(), json_path.to_string()));
+        Ok(())
+    }
+
+    fn add_unresolved(
+        &mut self,
+        expression: &Expression,
+        json_path: &str,
+        substitutes: &Substitutes,
+    ) {
+        let var_name = expression.variables().collect::<Vec<_>>().join(" ?? ");
+        if let Some(unresolved) = self.unresolved.iter_mut().find(|u| u.var_name == var_name) {
+            if !unresolved.json_paths.iter().any(|path| path == json_path) {
+                unresolved.json_paths.push(json_path.to_string());
+            }
+            return;
+        }
+
+        let mut searched = Vec::new();
+        let mut missing = Vec::new();
+        for term in &expression.alternatives {
+            let sources = match term {
+                Term::Variable(variable) => sources(variable),
+                Term::File(_) => &["the file root"],
+                Term::Literal(_) => continue,
+            };
+            for source in sources {
+                let specified = match *source {
+                    "env_secrets" => substitutes.env_secrets.is_some(),
+                    "env_vars" => substitutes.env_vars.is_some(),
+                    _ => true,
+                };
+                let list = if specified {
+                    &mut searched
+                } else {
+                    &mut missing
+                };
+                if !list.contains(source) {
+                    list.push(source);
+                }
+            }
+        }
+
+        self.unresolved.push(Unresolved {
+            var_name,
+            searched,
+            missing,
+            json_paths: vec![json_path.to_string()],
+        });
+    }
+}
+
+pub fn substitute_values(
+    input: &mut Value,
+    substitutes: &Substitutes,
+    schema: &SchemaCursor,
+    root_path: &str,
+) -> Result<()> {
+    let mut document = Document {
+        original: input.clone(),
+        schema: schema.clone(),
+        root_path: root_path.to_string(),
+        resolved: HashMap::new(),
+        resolving: Vec::new(),
+        expanding: Vec::new(),
+        unresolved: Vec::new(),
+    };
+    let keep = substitute_recursive(input, substitutes, &mut document, schema, root_path, "")?;
+
+    // All missing variables are reported at once, so that they can be fixed in one go
+    if !document.unresolved.is_empty() {
+        let mut error_msg = format!(
+            "Substitution failed with {} unresolved variable(s):",
+            document.unresolved.len()
+        );
+        for (i, unresolved) in document.unresolved.iter().enumerate() {
+            write!(error_msg, "\n  {}. {}", i + 1, unresolved)?;
+        }
+        bail!(error_msg);
+    }
+
+    // A document can't be removed, so an unresolved optional placeholder leaves it empty
+    if !keep {
+        *input = Value::Null;
+    }
+    Ok(())
+}
+
+// Returns false when the value should be removed from its parent, because it consists of an unresolved
+// optional placeholder
+fn substitute_recursive(
+    value: &mut Value,
+    substitutes: &Substitutes,
+    document: &mut Document,
+    schema: &SchemaCursor,
+    json_path: &str,
+    pointer: &str,
+) -> Result<bool> {
+    match value {
+        Value::String(s) => {
+            let original = s.clone();
+
+            // The value may already be substituted, because another value referred to it
+            let result = if let Some(result) = document.resolved.get(pointer) {
+                result.clone()
+            } else {
+                document.enter(pointer, json_path)?;
+                let result = substitute_string(s, substitutes, document, Some(schema), json_path);
+                document.resolving.pop();
+                let result = result?;
+                document
+                    .resolved
+                    .insert(pointer.to_string(), result.clone());
+                result
+            };
+
+            let Some(result) = result else {
+                debug!(
+                    "Removed value at path '{}', optional placeholder '{}' was not resolved",
+                    json_path, original
+                );
+                return Ok(false);
+            };
+
+            *value = result;
+
+            // Log if substitution occurred
+            if value.as_str() != Some(original.as_str()) {
+                debug!(
+                    "Substituted value at path '{}': '{}' -> '{}'",
+                    json_path, original, value
+                );
+            }
+        }
+        Value::Object(obj) => {
+            // Paths keep the keys as written in the input, schemas use the substituted keys
+            let mut result = Map::new();
+            let mut original_keys: HashMap<String, String> = HashMap::new();
+            for (key, mut v) in std::mem::take(obj) {
+                let new_path = format!("{json_path}.{key}");
+                let Some(new_key) = substitute_key(&key, substitutes, document, &new_path)? else {
+                    debug!(
+                        "Removed property at path '{}', optional placeholder in its key was not resolved",
+                        new_path
+                    );
+                    continue;
+                };
+                if let Some(other_key) = original_keys.insert(new_key.clone(), key.clone()) {
+                    bail!(
+                        "Keys '{other_key}' and '{key}' of the object at path '{json_path}' both become '{new_key}' after substitution"
+                    );
+                }
+
+                let keep = substitute_recursive(
+                    &mut v,
+                    substitutes,
+                    document,
+                    &schema.property(&new_key),
+                    &new_path,
+                    &format!("{pointer}/{}", pointer_token(&key)),
+                )?;
+                if keep {
+                    result.insert(new_key, v);
+                }
+            }
+            *obj = result;
+        }
+        Value::Array(arr) => {
+            // Paths and schemas of the items refer to their original index, so remove them afterwards
+            let mut keep = Vec::with_capacity(arr.len());
+            for (index, item) in arr.iter_mut().enumerate() {
+                let new_path = format!("{json_path}[{index}]");
+                keep.push(substitute_recursive(
+                    item,
+                    substitutes,
+                    document,
+                    &schema.item(index),
+                    &new_path,
+                    &format!("{pointer}/{index}"),
+                )?);
+            }
+            let mut keep = keep.into_iter();
+            arr.retain(|_| keep.next().unwrap_or(true));
+        }
+        _ => {} // Numbers, booleans, null don't need substitution
+    }
+    Ok(true)
+}
+
+// Placeholders in object keys always produce a string. Returns None when the key consists of an unresolved
+// optional placeholder, which removes the property.
+fn substitute_key(
+    key: &str,
+    substitutes: &Substitutes,
+    document: &mut Document,
+    json_path: &str,
+) -> Result<Option<String>> {
+    if !substitutes.placeholder_syntax.matches(key) {
+        return Ok(Some(key.to_string()));
+    }
+
+    match substitute_string(key, substitutes, document, None, json_path)
+        .with_context(|| format!("Failed to substitute key at path '{json_path}'"))?
+    {
+        None => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s)),
+        Some(value @ (Value::Number(_) | Value::Bool(_) | Value::Null)) => {
+            Ok(Some(value.to_string()))
+        }
+        Some(Value::Array(_)) => {
+            bail!("Key at path '{json_path}' produces an array, keys must be strings")
+        }
+        Some(Value::Object(_)) => {
+            bail!("Key at path '{json_path}' produces an object, keys must be strings")
+        }
+    }
+}
+
+// Convert a substituted value, and the items and properties of structured ones, to the types that the schema
+// expects. Structured values that the schema expects as string are written as JSON, as they used to be.
+fn coerce_to_schema(value: Value, schema: &SchemaCursor) -> Value {
+    let types = schema.types();
+    match value {
+        Value::String(s) => coerce_to_schema_type(s, &types),
+        value if types.is_empty() || types.iter().any(|t| has_type(&value, t)) => match value {
+            Value::Array(items) => Value::Array(
+                items
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, item)| coerce_to_schema(item, &schema.item(index)))
+                    .collect(),
+            ),
+            Value::Object(obj) => Value::Object(
+                obj.into_iter()
+                    .map(|(key, v)| {
+                        let v = coerce_to_schema(v, &schema.property(&key));
+                        (key, v)
+                    })
+                    .collect(),
+            ),
+            value => value,
+        },
+        value if types.contains(&"string") => Value::String(value.to_string()),
+        value => value,
+    }
+}
+
+fn has_type(value: &Value, t: &str) -> bool {
+    match (value, t) {
+        (Value::Number(n), "integer") => n.is_i64() || n.is_u64(),
+        (Value::Null, "null")
+        | (Value::Bool(_), "boolean")
+        | (Value::Number(_), "number")
+        | (Value::String(_), "string")
+        | (Value::Array(_), "array")
+        | (Value::Object(_), "object") => true,
+        _ => false,
+    }
+}
+
+// Convert a substituted value to the first type in the schema it can be parsed as. Values are kept as
+// string when the schema allows strings or no conversion succeeds, so that validation reports the mismatch.
+fn coerce_to_schema_type(s: String, types: &[&str]) -> Value {
+    if types.is_empty() || types.contains(&"string") {
+        return Value::String(s);
+    }
+
+    let trimmed = s.trim();
+    for t in types {
+        let coerced = match *t {
+            "integer" => trimmed
+                .parse::<i64>()
+                .map(Value::from)
+                .or_else(|_| trimmed.parse::<u64>().map(Value::from))
+                .ok(),
+            "number" => trimmed.parse::<i64>().map(Value::from).ok().or_else(|| {
+                trimmed
+                    .parse::<f64>()
+                    .ok()
+                    .and_then(Number::from_f64)
+                    .map(Value::Number)
+            }),
+            "boolean" => match trimmed {
+                "true" => Some(Value::Bool(true)),
+                "false" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            "null" => (trimmed.is_empty() || trimmed == "null").then_some(Value::Null),
+            "object" | "array" => serde_json::from_str::<Value>(trimmed)
+                .ok()
+                .filter(|v| v.is_object() == (*t == "object") && v.is_array() == (*t == "array")),
+            _ => None,
+        };
+
+        if let Some(coerced) = coerced {
+            return coerced;
+        }
+    }
+
+    Value::String(s)
+}
+
+// Returns None when an unresolved optional placeholder makes up the whole value. Such a placeholder can
+// produce any type that the schema expects (including structured substitute values), placeholders embedded
+// in a larger string always produce a string. Without a schema, the value is used as it is.
+fn substitute_string(
+    s: &str,
+    substitutes: &Substitutes,
+    document: &mut Document,
+    schema: Option<&SchemaCursor>,
+    json_path: &str,
+) -> Result<Option<Value>> {
+    let segments = template::tokenize(s, &substitutes.placeholder_syntax)
+        .with_context(|| format!("Invalid placeholder in value at path '{json_path}'"))?;
+    let whole_value = matches!(segments.as_slice(), [Segment::Placeholder { .. }]);
+
+    // Every segment is only looked at once, so substituted values are not substituted again (unless
+    // substitute values are expanded on purpose)
+    let mut result = String::new();
+    for segment in segments {
+        let (source, expression) = match segment {
+            Segment::Text(text) => {
+                result.push_str(&text);
+                continue;
+            }
+            Segment::Placeholder { source, expression } => (source, expression),
+        };
+
+        let expression = Expression::parse(expression)
+            .with_context(|| format!("Invalid placeholder '{source}' at path '{json_path}'"))?;
+        let resolved =
+            resolve(&expression, substitutes, document, json_path).with_context(|| {
+                format!("Failed to resolve placeholder '{source}' at path '{json_path}'")
+            })?;
+
+        // An unresolved optional placeholder removes the whole value, or disappears from a larger string
+        if resolved.is_none() && expression.optional {
+            if whole_value {
+                return Ok(None);
+            }
+            continue;
+        }
+
+        // Unresolved placeholders are kept as they are, so that the rest of the document can still be checked
+        let Some((replacement, value_source)) = resolved else {
+            document.add_unresolved(&expression, json_path, substitutes);
+            if whole_value {
+                return Ok(Some(Value::String(s.to_string())));
+            }
+            result.push_str(source);
+            continue;
+        };
+
+        debug!(
+            "Replaced '{}' with value from {} at JSON path '{}'",
+            source, value_source, json_path
+        );
+        match replacement {
+            replacement if whole_value => {
+                // Scalars are inserted as text like before, only the schema can ask for another type. Arrays and
+                // objects are inserted as they are.
+                let replacement = match replacement {
+                    Value::Number(_) | Value::Bool(_) | Value::Null => {
+                        Value::String(replacement.to_string())
+                    }
+                    replacement => replacement,
+                };
+                return Ok(Some(match schema {
+                    Some(schema) => coerce_to_schema(replacement, schema),
+                    None => replacement,
+                }));
+            }
+            Value::String(replacement) => result.push_str(&replacement),
+            Value::Number(_) | Value::Bool(_) | Value::Null => {
+                result.push_str(&replacement.to_string());
+            }
+            Value::Array(_) => bail!(
+                "Placeholder '{source}' at path '{json_path}' produces an array, which cannot be embedded in a string"
+            ),
+            Value::Object(_) => bail!(
+                "Placeholder '{source}' at path '{json_path}' produces an object, which cannot be embedded in a string"
+            ),
+        }
+    }
+
+    Ok(Some(Value::String(result)))
+}
+
+// Resolve an expression to its value and the source it was taken from, if any alternative or default applies
+fn resolve(
+    expression: &Expression,
+    substitutes: &Substitutes,
+    document: &mut Document,
+    json_path: &str,
+) -> Result<Option<(Value, &'static str)>> {
+    let mut resolved = None;
+    for term in &expression.alternatives {
+        resolved = match term {
+            Term::Variable(variable) => match variable.path.split_first() {
+                Some((Key::Field(root), path)) if root == "$" => {
+                    resolve_reference(path, substitutes, document)?.map(|value| (value, "document"))
+                }
+                _ => match lookup(variable, substitutes, json_path) {
+                    Some((value, source)) if substitutes.max_expansion_depth.is_some() => {
+                        expand(value, &variable.name, substitutes, document, json_path)?
+                            .map(|value| (value, source))
+                    }
+                    resolved => resolved,
+                },
+            },
+            Term::File(path) => read_file(path, substitutes)?.map(|s| (Value::String(s), "file")),
+            Term::Literal(value) => Some((Value::String(value.clone()), "fallback")),
+        };
+        if resolved.is_some() {
+            break;
+        }
+    }
+
+    // A default fills in a missing value, all other filters transform a value that is present
+    for filter in &expression.filters {
+        resolved = match (resolved, filter) {
+            (None, Filter::Default(value)) => Some((Value::String(value.clone()), "default")),
+            (None, _) => None,
+            (Some((value, source)), filter) => Some((filter.apply(value)?, source)),
+        };
+    }
+    Ok(resolved)
+}
+
+// Read the file of a `file:` placeholder, None when it does not exist. Files outside the file root are rejected,
+// before anything is read for `..` and absolute paths (so that they can't be used to find out which files exist),
+// and afterwards for symlinks.
+fn read_file(path: &str, substitutes: &Substitutes) -> Result<Option<String>> {
+    let outside_root = || {
+        anyhow!(
+            "File '{path}' is outside of the file root {}, use --file-root to allow it",
+            substitutes.file_root.display()
+        )
+    };
+
+    let mut full_path = substitutes.file_dir.clone();
+    for component in Path::new(path).components() {
+        match component {
+            Component::ParentDir => {
+                if !full_path.pop() {
+                    return Err(outside_root());
+                }
+            }
+            Component::Normal(name) => full_path.push(name),
+            Component::RootDir | Component::Prefix(_) => full_path.push(component),
+            Component::CurDir => {}
+        }
+    }
+    if !full_path.starts_with(&substitutes.file_root) {
+        return Err(outside_root());
+    }
+
+    let canonical_path = match full_path.canonicalize() {
+        Ok(canonical_path) => canonical_path,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read file: {}", full_path.display()));
+        }
+    };
+    if !canonical_path.starts_with(&substitutes.file_root) {
+        return Err(outside_root());
+    }
+
+    let contents = fs::read_to_string(&canonical_path)
+        .with_context(|| format!("Failed to read file: {}", full_path.display()))?;
+    debug!(
+        "Read {} byte(s) from {}",
+        contents.len(),
+        full_path.display()
+    );
+    Ok(Some(contents))
+}
+
+// Substitute placeholders in the value of a substitution variable itself, e.g. a db_url variable of
+// `postgres://{{ db_host }}/app`. None when the value consists of an unresolved optional placeholder.
+fn expand(
+    value: Value,
+    var_name: &str,
+    substitutes: &Substitutes,
+    document: &mut Document,
+    json_path: &str,
+) -> Result<Option<Value>> {
+    if !substitutes.placeholder_syntax.matches(&value.to_string()) {
+        return Ok(Some(value));
+    }
+
+    let same_name = |name: &String| {
+        if substitutes.case_sensitive {
+            name == var_name
+        } else {
+            name.to_lowercase() == var_name.to_lowercase()
+        }
+    };
+    if let Some(start) = document.expanding.iter().position(same_name) {
+        bail!(
+            "Substitution variables refer to each other in a cycle: {}",
+            chain(&document.expanding[start..], var_name)
+        );
+    }
+    let max_depth = substitutes.max_expansion_depth.unwrap_or_default();
+    if document.expanding.len() >= max_depth {
+        bail!(
+            "Substitution variables are nested more than {max_depth} level(s) deep: {}",
+            chain(&document.expanding, var_name)
+        );
+    }
+
+    document.expanding.push(var_name.to_string());
+    let expanded = expand_value(value, substitutes, document, json_path);
+    document.expanding.pop();
+    expanded.with_context(|| format!("Failed to expand substitution variable '{var_name}'"))
+}
+
+// A key as part of a JSON pointer (RFC 6901), e.g. `a~1b` for the key `a/b`
+fn pointer_token(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+fn chain(names: &[String], last: &str) -> String {
+    names
+        .iter()
+        .map(String::as_str)
+        .chain([last])
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
+fn expand_value(
+    value: Value,
+    substitutes: &Substitutes,
+    document: &mut Document,
+    json_path: &str,
+) -> Result<Option<Value>> {
+    match value {
+        Value::String(s) => substitute_string(&s, substitutes, document, None, json_path),
+        Value::Array(items) => {
+            let mut expanded = Vec::with_capacity(items.len());
+            for item in items {
+                expanded.extend(expand_value(item, substitutes, document, json_path)?);
+            }
+            Ok(Some(Value::Array(expanded)))
+        }
+        Value::Object(obj) => {
+            let mut expanded = Map::new();
+            for (key, v) in obj {
+                if let Some(v) = expand_value(v, substitutes, document, json_path)? {
+                    expanded.insert(key, v);
+                }
+            }
+            Ok(Some(Value::Object(expanded)))
+        }
+        value => Ok(Some(value)),
+    }
+}
+
+// Find the substituted value at a location in the document, None if there is no value
+fn resolve_reference(
+    path: &[Key],
+    substitutes: &Substitutes,
+    document: &mut Document,
+) -> Result<Option<Value>> {
+    let mut node = &document.original;
+    let mut schema = document.schema.clone();
+    let mut json_path = document.root_path.clone();
+    let mut pointer = String::new();
+    let mut walked = 0;
+
+    // Walk the document until the location, or until a string that a placeholder may turn into an object
+    for key in path {
+        if node.is_string() {
+            break;
+        }
+        let (child, child_schema) = match (key, node) {
+            (Key::Field(field), Value::Object(obj)) => (obj.get(field), schema.property(field)),
+            (Key::Index(index), Value::Array(items)) => (items.get(*index), schema.item(*index)),
+            _ => (None, schema),
+        };
+        let Some(child) = child else {
+            return Ok(None);
+        };
+
+        node = child;
+        schema = child_schema;
+        (json_path, pointer) = match key {
+            Key::Field(field) => (
+                format!("{json_path}.{field}"),
+                format!("{pointer}/{}", pointer_token(field)),
+            ),
+            Key::Index(index) => (
+                format!("{json_path}[{index}]"),
+                format!("{pointer}/{index}"),
+            ),
+        };
+        walked += 1;
+    }
+
+    let mut value = node.clone();
+    if !substitute_recursive(
+        &mut value,
+        substitutes,
+        document,
+        &schema,
+        &json_path,
+        &pointer,
+    )? {
+        return Ok(None);
+    }
+
+    // The rest of the path points into the value that a placeholder produced
+    let mut value = &value;
+    for key in &path[walked..] {
+        let child = match (key, value) {
+            (Key::Field(field), Value::Object(obj)) => obj.get(field),
+            (Key::Index(index), Value::Array(items)) => items.get(*index),
+            _ => None,
+        };
+        let Some(child) = child else {
+            return Ok(None);
+        };
+        value = child;
+    }
+    Ok(Some(value.clone()))
+}
+
+// A namespaced name (e.g. `secrets.db_password`) is only looked up in that source, other names are looked up
+// in env_secrets first and then env_vars
+fn lookup(
+    variable: &Variable,
+    substitutes: &Substitutes,
+    json_path: &str,
+) -> Option<(Value, &'static str)> {
+    // Names of process environment variables are case-sensitive, so they are used as written
+    if let Some(name) = variable.name.strip_prefix("env.") {
+        return std::env::var(name)
+            .ok()
+            .map(|value| (Value::String(value), "process environment"));
+    }
+
+    match variable.path.split_first() {
+        Some((Key::Field(namespace), path)) if namespace == "vars" && !path.is_empty() => {
+            return get(substitutes.env_vars, path, substitutes.case_sensitive)
+                .map(|value| (value, "env_vars"));
+        }
+        Some((Key::Field(namespace), path)) if namespace == "secrets" && !path.is_empty() => {
+            return get(substitutes.env_secrets, path, substitutes.case_sensitive)
+                .map(|value| (value, "env_secrets"));
+        }
+        _ => {}
+    }
+
+    let secret = get(
+        substitutes.env_secrets,
+        &variable.path,
+        substitutes.case_sensitive,
+    );
+    let var = get(
+        substitutes.env_vars,
+        &variable.path,
+        substitutes.case_sensitive,
+    );
+    if let (Some(secret), Some(var)) = (&secret, &var)
+        && secret != var
+    {
+        let name = &variable.name;
+        warn!(
+            "Substitution variable '{}' at path '{}' is defined with different values in env_secrets and env_vars, using env_secrets (use 'secrets.{}' or 'vars.{}' to choose explicitly)",
+            name, json_path, name, name
+        );
+    }
+
+    let found = secret
+        .map(|value| (value, "env_secrets"))
+        .or_else(|| var.map(|value| (value, "env_vars")));
+
+    // Git metadata is only used when the substitutes don't define it, so that it can still be passed in where
+    // there is no repository (e.g. in a Docker build context)
+    match variable.path.as_slice() {
+        [Key::Field(namespace), Key::Field(field)] if found.is_none() && namespace == "git" => {
+            let value = substitutes.git.metadata()?.get(field)?;
+            Some((value.clone(), "git repository"))
+        }
+        _ => found,
+    }
+}
+
+// Walk a path (e.g. `db.ports[0]`) into the substitutes. Top-level keys may contain dots themselves (e.g.
+// `app.name` in a dotenv file), so the longest matching key is used. Nested keys are matched exactly, or
+// ignoring case when lookups are case-insensitive.
+fn get(
+    substitutes: Option<&HashMap<String, Value>>,
+    path: &[Key],
+    case_sensitive: bool,
+) -> Option<Value> {
+    let substitutes = substitutes?;
+    let fields = path
+        .iter()
+        .take_while(|key| matches!(key, Key::Field(_)))
+        .count();
+
+    (1..=fields).rev().find_map(|length| {
+        let key = path[..length]
+            .iter()
+            .filter_map(|key| match key {
+                Key::Field(field) => Some(field.as_str()),
+                Key::Index(_) => None,
+            })
+            .collect::<Vec<_>>()
+            .join(".");
+        let key = if case_sensitive {
+            key
+        } else {
+            key.to_lowercase()
+        };
+
+        let mut value = substitutes.get(&key)?;
+        for key in &path[length..] {
+            value = match (key, value) {
+                (Key::Index(index), Value::Array(items)) => items.get(*index)?,
+                (Key::Field(field), Value::Object(obj)) => obj.get(field).or_else(|| {
+                    obj.iter()
+                        .find(|(k, _)| !case_sensitive && k.eq_ignore_ascii_case(field))
+                        .map(|(_, v)| v)
+                })?,
+                _ => return None,
+            };
+        }
+        Some(value.clone())
+    })
+}
+
+// The sources that a variable is looked up in, in order
+fn sources(variable: &Variable) -> &'static [&'static str] {
+    match variable.path.split_first() {
+        _ if variable.name.starts_with("env.") => &["the process environment"],
+        Some((Key::Field(root), _)) if root == "$" => &["the document"],
+        Some((Key::Field(namespace), path)) if namespace == "vars" && !path.is_empty() => {
+            &["env_vars"]
+        }
+        Some((Key::Field(namespace), path)) if namespace == "secrets" && !path.is_empty() => {
+            &["env_secrets"]
+        }
+        Some((Key::Field(namespace), [Key::Field(_)])) if namespace == "git" => {
+            &["env_secrets", "env_vars", "the git repository"]
+        }
+        _ => &["env_secrets", "env_vars"],
+    }
+}
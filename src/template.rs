@@ -0,0 +1,580 @@
+use anyhow::{Context, Result, anyhow, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::iter::Peekable;
+use std::str::Chars;
+
+// A part of a string value, either literal text or a placeholder
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment<'a> {
+    Text(String),
+    Placeholder {
+        // The full placeholder including its delimiters, e.g. `{{ db_host }}`
+        source: &'a str,
+        // The trimmed expression inside the delimiters, e.g. `db_host`
+        expression: &'a str,
+    },
+}
+
+// The delimiters that mark a placeholder, `{{ }}` unless configured otherwise
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Syntax {
+    pub open: String,
+    pub close: String,
+}
+
+impl Default for Syntax {
+    fn default() -> Self {
+        Syntax {
+            open: "{{".to_string(),
+            close: "}}".to_string(),
+        }
+    }
+}
+
+impl Syntax {
+    // Parse "mustache" (`{{ name }}`), "shell" (`${name}`, as used by envsubst) or custom delimiters separated
+    // by a space, e.g. "<% %>"
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.trim() {
+            "mustache" => return Ok(Syntax::default()),
+            "shell" => {
+                return Ok(Syntax {
+                    open: "${".to_string(),
+                    close: "}".to_string(),
+                });
+            }
+            _ => {}
+        }
+
+        let [open, close] = s.split_whitespace().collect::<Vec<_>>()[..] else {
+            bail!(
+                "Invalid placeholder syntax '{s}', expected 'mustache', 'shell' or an opening and closing \
+                 delimiter separated by a space, e.g. '<% %>'"
+            );
+        };
+        if open == close {
+            bail!(
+                "Invalid placeholder syntax '{s}', the opening and closing delimiter must differ"
+            );
+        }
+        // Letters and digits would turn ordinary text into placeholders, and backslashes are used for escaping
+        if let Some(c) = s.chars().find(|c| c.is_alphanumeric() || *c == '\\') {
+            bail!(
+                "Invalid placeholder syntax '{s}', delimiters cannot contain letters, digits or backslashes (found '{c}')"
+            );
+        }
+
+        Ok(Syntax {
+            open: open.to_string(),
+            close: close.to_string(),
+        })
+    }
+
+    // Whether a string contains anything that looks like a placeholder
+    pub fn matches(&self, s: &str) -> bool {
+        s.contains(self.open.as_str())
+    }
+}
+
+// Split a string value into text and placeholders in a single pass. Backslashes before the opening delimiter
+// escape it: `\{{` produces a literal `{{`, and `\\{{ dir }}` a single backslash followed by a placeholder. A
+// closing delimiter inside a quoted value (e.g. `{{ a ?? "}}" }}`) does not end the placeholder.
+pub fn tokenize<'a>(s: &'a str, syntax: &Syntax) -> Result<Vec<Segment<'a>>> {
+    let mut segments = Vec::new();
+    let mut text = String::new();
+    let mut index = 0;
+
+    while let Some(c) = s[index..].chars().next() {
+        let backslashes = s[index..].len() - s[index..].trim_start_matches('\\').len();
+        if backslashes > 0 && s[index + backslashes..].starts_with(syntax.open.as_str()) {
+            // Every pair of backslashes is a literal one, an odd one out makes the delimiter literal
+            text.extend(std::iter::repeat_n('\\', backslashes / 2));
+            index += backslashes;
+            if backslashes % 2 == 1 {
+                text.push_str(&syntax.open);
+                index += syntax.open.len();
+            }
+        } else if s[index..].starts_with(syntax.open.as_str()) {
+            let start = index + syntax.open.len();
+            let end = placeholder_end(s, start, &syntax.close).ok_or_else(|| {
+                anyhow!(
+                    "Unterminated placeholder starting at character offset {}, expected '{}'",
+                    char_offset(s, index),
+                    syntax.close
+                )
+            })?;
+            let expression = s[start..end].trim();
+            if expression.is_empty() {
+                bail!(
+                    "Empty placeholder at character offset {}",
+                    char_offset(s, index)
+                );
+            }
+
+            if !text.is_empty() {
+                segments.push(Segment::Text(std::mem::take(&mut text)));
+            }
+            let after = end + syntax.close.len();
+            segments.push(Segment::Placeholder {
+                source: &s[index..after],
+                expression,
+            });
+            index = after;
+        } else {
+            text.push(c);
+            index += c.len_utf8();
+        }
+    }
+
+    if !text.is_empty() {
+        segments.push(Segment::Text(text));
+    }
+    Ok(segments)
+}
+
+// Find the byte index of the closing delimiter of a placeholder. Quotes only start a quoted value where the
+// expression parser expects one, so that unquoted values like `name:-it's` keep working.
+fn placeholder_end(s: &str, start: usize, close: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut previous = None;
+
+    for (offset, c) in s[start..].char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            None if s[start + offset..].starts_with(close) => return Some(start + offset),
+            None if matches!(c, '"' | '\'')
+                && previous.is_none_or(|p: char| p.is_whitespace() || "?-:(,".contains(p)) =>
+            {
+                quote = Some(c);
+            }
+            _ => {}
+        }
+        previous = Some(c);
+    }
+    None
+}
+
+fn char_offset(s: &str, byte_index: usize) -> usize {
+    s[..byte_index].chars().count()
+}
+
+// The expression inside a placeholder, e.g. `db_host`, `a ?? b ?? "x"`, `log_level | default: "info"` or `sentry_dsn?`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    // Tried in order, the first one that resolves is used
+    pub alternatives: Vec<Term>,
+    // Marked with a trailing `?`, the value is left out instead of failing when nothing resolves
+    pub optional: bool,
+    pub filters: Vec<Filter>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term {
+    Variable(Variable),
+    // The contents of a file, e.g. `file:certs/ca.pem`
+    File(String),
+    Literal(String),
+}
+
+// A variable, which can point into a structured substitute value, e.g. `db.ports[0]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variable {
+    // As written in the placeholder
+    pub name: String,
+    pub path: Vec<Key>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Key {
+    Field(String),
+    Index(usize),
+}
+
+impl Variable {
+    fn parse(name: &str) -> Result<Self> {
+        let mut path = Vec::new();
+        let mut field = String::new();
+        let mut chars = name.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '.' | '[' => {
+                    // A field can only be empty right after an index, e.g. `ports[0].name`
+                    if field.is_empty() && !matches!(path.last(), Some(Key::Index(_))) {
+                        bail!("Invalid variable '{name}', expected a name before '{c}'");
+                    }
+                    if !field.is_empty() {
+                        path.push(Key::Field(std::mem::take(&mut field)));
+                    }
+                    if c == '.' {
+                        continue;
+                    }
+
+                    let mut index = String::new();
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == ']' {
+                            closed = true;
+                            break;
+                        }
+                        index.push(c);
+                    }
+                    if !closed {
+                        bail!("Invalid variable '{name}', missing ']'");
+                    }
+                    let index = index.trim().parse().with_context(|| {
+                        format!("Invalid variable '{name}', '{index}' is not a valid index")
+                    })?;
+                    path.push(Key::Index(index));
+                }
+                ']' => bail!("Invalid variable '{name}', unexpected ']'"),
+                _ => field.push(c),
+            }
+        }
+
+        if !field.is_empty() {
+            path.push(Key::Field(field));
+        } else if !matches!(path.last(), Some(Key::Index(_))) {
+            bail!("Invalid variable '{name}', expected a name after '.'");
+        }
+
+        Ok(Variable {
+            name: name.to_string(),
+            path,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    // Used when none of the alternatives resolved
+    Default(String),
+    Upper,
+    Lower,
+    Trim,
+    Base64Encode,
+    Base64Decode,
+    UrlEncode,
+    JsonEscape,
+    Sha256,
+    // Turns the value into an array, later filters are applied to each of its items
+    Split(String),
+    Replace(String, String),
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut parser = Parser {
+            chars: source.chars().peekable(),
+        };
+
+        let mut alternatives = Vec::new();
+        parser.term(&mut alternatives)?;
+        while parser.eat("??") {
+            parser.term(&mut alternatives)?;
+        }
+        let optional = parser.eat("?");
+
+        let mut filters = Vec::new();
+        while parser.eat("|") {
+            filters.push(parser.filter()?);
+        }
+
+        parser.skip_whitespace();
+        if let Some(c) = parser.chars.peek() {
+            bail!("Unexpected '{c}' in placeholder '{source}'");
+        }
+
+        Ok(Expression {
+            alternatives,
+            optional,
+            filters,
+        })
+    }
+
+    // Names of all variables and files in the expression, in the order they are tried
+    pub fn variables(&self) -> impl Iterator<Item = String> {
+        self.alternatives.iter().filter_map(|term| match term {
+            Term::Variable(variable) => Some(variable.name.clone()),
+            Term::File(path) => Some(format!("file:{path}")),
+            Term::Literal(_) => None,
+        })
+    }
+}
+
+impl Filter {
+    // Apply the filter to a resolved value. Numbers and booleans are treated as strings, and all filters
+    // except split are applied to each item of an array.
+    pub fn apply(&self, value: Value) -> Result<Value> {
+        let s = match (self, value) {
+            (Filter::Default(_), value) => return Ok(value),
+            (Filter::Split(_), Value::Array(_)) => {
+                bail!("Filter 'split' cannot be applied to an array")
+            }
+            (_, Value::Array(items)) => {
+                return Ok(Value::Array(
+                    items
+                        .into_iter()
+                        .map(|item| self.apply(item))
+                        .collect::<Result<_>>()?,
+                ));
+            }
+            (_, Value::String(s)) => s,
+            (_, value @ (Value::Number(_) | Value::Bool(_))) => value.to_string(),
+            (_, Value::Object(_)) => bail!("Filters cannot be applied to an object"),
+            (_, Value::Null) => bail!("Filters cannot be applied to null"),
+        };
+
+        match self {
+            Filter::Split(separator) => Ok(Value::Array(
+                s.split(separator.as_str())
+                    .map(|part| Value::String(part.to_string()))
+                    .collect(),
+            )),
+            _ => Ok(Value::String(self.apply_str(&s)?)),
+        }
+    }
+
+    fn apply_str(&self, s: &str) -> Result<String> {
+        let result = match self {
+            Filter::Upper => s.to_uppercase(),
+            Filter::Lower => s.to_lowercase(),
+            Filter::Trim => s.trim().to_string(),
+            Filter::Base64Encode => BASE64.encode(s),
+            Filter::Base64Decode => {
+                let bytes = BASE64
+                    .decode(s.trim())
+                    .context("Filter 'base64_decode' got a value that is not valid base64")?;
+                String::from_utf8(bytes)
+                    .context("Filter 'base64_decode' got a value that does not decode to UTF-8")?
+            }
+            Filter::UrlEncode => url_encode(s),
+            Filter::JsonEscape => {
+                let quoted = Value::String(s.to_string()).to_string();
+                quoted[1..quoted.len() - 1].to_string()
+            }
+            Filter::Sha256 => Sha256::digest(s).iter().fold(String::new(), |mut hex, b| {
+                let _ = write!(hex, "{b:02x}");
+                hex
+            }),
+            Filter::Replace(from, to) => s.replace(from.as_str(), to),
+            Filter::Default(_) | Filter::Split(_) => s.to_string(),
+        };
+        Ok(result)
+    }
+}
+
+// Percent-encode everything except the unreserved characters of RFC 3986
+fn url_encode(s: &str) -> String {
+    let mut encoded = String::new();
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(char::from(b));
+        } else {
+            let _ = write!(encoded, "%{b:02X}");
+        }
+    }
+    encoded
+}
+
+// Names of all filters, besides "default" which has its own syntax
+const FILTERS: &[&str] = &[
+    "upper",
+    "lower",
+    "trim",
+    "base64_encode",
+    "base64_decode",
+    "url_encode",
+    "json_escape",
+    "sha256",
+    "split",
+    "replace",
+];
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    // Consume the given token (after optional whitespace) if it is next
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        let mut lookahead = self.chars.clone();
+        if token.chars().all(|c| lookahead.next() == Some(c)) {
+            self.chars = lookahead;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn at_operator(&self) -> bool {
+        let mut lookahead = self.chars.clone();
+        matches!(
+            (lookahead.next(), lookahead.next()),
+            (Some('|'), _) | (Some('?'), Some('?')) | (Some(':'), Some('-'))
+        )
+    }
+
+    // A variable, a file or a quoted literal, where `name:-default` is shorthand for `name ?? "default"`
+    fn term(&mut self, alternatives: &mut Vec<Term>) -> Result<()> {
+        self.skip_whitespace();
+        if matches!(self.chars.peek(), Some('"' | '\'')) {
+            alternatives.push(Term::Literal(self.quoted()?));
+            return Ok(());
+        }
+
+        let mut name = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c.is_whitespace() || matches!(c, '"' | '\'' | '?') || self.at_operator() {
+                break;
+            }
+            name.push(c);
+            self.chars.next();
+        }
+        if name.is_empty() {
+            bail!("Expected a variable name or quoted value");
+        }
+
+        // Paths with spaces can be quoted, e.g. `file:"my certs/ca.pem"`
+        if let Some(path) = name.strip_prefix("file:") {
+            let path = if path.is_empty() && matches!(self.chars.peek(), Some('"' | '\'')) {
+                self.quoted()?
+            } else {
+                path.to_string()
+            };
+            if path.is_empty() {
+                bail!("Expected a path after 'file:'");
+            }
+            alternatives.push(Term::File(path));
+        } else {
+            alternatives.push(Term::Variable(Variable::parse(&name)?));
+        }
+
+        if self.eat(":-") {
+            alternatives.push(Term::Literal(self.literal()?));
+        }
+        Ok(())
+    }
+
+    fn filter(&mut self) -> Result<Filter> {
+        self.skip_whitespace();
+        let mut name = String::new();
+        while let Some(c) = self.chars.next_if(|c| c.is_alphanumeric() || *c == '_') {
+            name.push(c);
+        }
+
+        if name == "default" {
+            if !self.eat(":") {
+                bail!("Expected ':' after 'default'");
+            }
+            return Ok(Filter::Default(self.literal()?));
+        }
+
+        if name.is_empty() {
+            bail!("Expected a filter name after '|'");
+        }
+        if !FILTERS.contains(&name.as_str()) {
+            bail!("Unknown filter '{name}'");
+        }
+
+        let arguments = self.arguments(&name)?;
+        let filter = match (name.as_str(), arguments.as_slice()) {
+            ("upper", []) => Filter::Upper,
+            ("lower", []) => Filter::Lower,
+            ("trim", []) => Filter::Trim,
+            ("base64_encode", []) => Filter::Base64Encode,
+            ("base64_decode", []) => Filter::Base64Decode,
+            ("url_encode", []) => Filter::UrlEncode,
+            ("json_escape", []) => Filter::JsonEscape,
+            ("sha256", []) => Filter::Sha256,
+            ("split", [separator]) if !separator.is_empty() => Filter::Split(separator.clone()),
+            ("split", [_]) => bail!("Filter 'split' needs a non-empty separator"),
+            ("split", _) => bail!("Filter 'split' takes 1 argument, e.g. split(\",\")"),
+            ("replace", [from, to]) if !from.is_empty() => {
+                Filter::Replace(from.clone(), to.clone())
+            }
+            ("replace", [_, _]) => bail!("Filter 'replace' needs a non-empty value to replace"),
+            ("replace", _) => {
+                bail!("Filter 'replace' takes 2 arguments, e.g. replace(\"from\", \"to\")")
+            }
+            _ => bail!("Filter '{name}' takes no arguments"),
+        };
+        Ok(filter)
+    }
+
+    // Quoted arguments between parentheses after a filter name, if any
+    fn arguments(&mut self, name: &str) -> Result<Vec<String>> {
+        let mut arguments = Vec::new();
+        if !self.eat("(") {
+            return Ok(arguments);
+        }
+        if self.eat(")") {
+            return Ok(arguments);
+        }
+
+        loop {
+            self.skip_whitespace();
+            if !matches!(self.chars.peek(), Some('"' | '\'')) {
+                bail!("Arguments of filter '{name}' must be quoted");
+            }
+            arguments.push(self.quoted()?);
+
+            if self.eat(")") {
+                return Ok(arguments);
+            }
+            if !self.eat(",") {
+                bail!("Expected ',' or ')' after argument of filter '{name}'");
+            }
+        }
+    }
+
+    // A quoted value, or an unquoted value that runs until the next operator
+    fn literal(&mut self) -> Result<String> {
+        self.skip_whitespace();
+        if matches!(self.chars.peek(), Some('"' | '\'')) {
+            return self.quoted();
+        }
+
+        let mut value = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if self.at_operator() {
+                break;
+            }
+            value.push(c);
+            self.chars.next();
+        }
+        Ok(value.trim().to_string())
+    }
+
+    fn quoted(&mut self) -> Result<String> {
+        let Some(quote) = self.chars.next() else {
+            bail!("Expected a quoted value");
+        };
+
+        let mut value = String::new();
+        loop {
+            match self.chars.next() {
+                Some(c) if c == quote => return Ok(value),
+                Some('\\') => match self.chars.next() {
+                    Some(c) => value.push(c),
+                    None => break,
+                },
+                Some(c) => value.push(c),
+                None => break,
+            }
+        }
+        bail!("Unterminated quoted value")
+    }
+}
@@ -0,0 +1,126 @@
+// Tests that run the built binary, for behaviour that the test cases in the directories next to this file can't
+// cover: substitutes from environment variables and stdin, and output to stdout.
+use anyhow::{Context, Result, ensure};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+use tempfile::TempDir;
+
+const SCHEMA: &str = "tests/substitutes-secret/schema.json";
+const INPUT: &str = "tests/substitutes-secret/input.json";
+
+// Run env-verify with the given arguments, environment variables and stdin
+fn env_verify(args: &[&str], envs: &[(&str, &str)], stdin: &str) -> Result<Output> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_env-verify"))
+        .args(args)
+        .envs(envs.iter().copied())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to start env-verify")?;
+    child
+        .stdin
+        .take()
+        .context("Failed to open stdin")?
+        .write_all(stdin.as_bytes())?;
+    let output = child.wait_with_output()?;
+    ensure!(
+        output.status.success(),
+        "env-verify failed:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    Ok(output)
+}
+
+// Substitute the test input with secrets from the given source, and return the written output
+fn substitute_secrets_from(source: &str, envs: &[(&str, &str)], stdin: &str) -> Result<Value> {
+    let dir = TempDir::new()?;
+    let output_path = dir.path().join("output.json");
+    let output_path = output_path.to_str().context("Invalid temp dir")?;
+    env_verify(
+        &[
+            "--schema",
+            SCHEMA,
+            "--input",
+            INPUT,
+            "--env-secrets",
+            source,
+            "--output",
+            output_path,
+        ],
+        envs,
+        stdin,
+    )?;
+    Ok(serde_json::from_str(&std::fs::read_to_string(
+        output_path,
+    )?)?)
+}
+
+fn yaml_documents(yaml: &str) -> Result<Vec<Value>> {
+    serde_yaml_ng::Deserializer::from_str(yaml)
+        .map(|document| Ok(Value::deserialize(document)?))
+        .collect()
+}
+
+#[test]
+fn test_substitutes_from_env_var() -> Result<()> {
+    let output = substitute_secrets_from(
+        "env:ENV_VERIFY_TEST_SECRETS",
+        &[("ENV_VERIFY_TEST_SECRETS", r#"{ "sub1": "from-env" }"#)],
+        "",
+    )?;
+    assert_eq!(output, json!({ "foo": "bar", "bar": "from-env" }));
+    Ok(())
+}
+
+#[test]
+fn test_substitutes_from_stdin() -> Result<()> {
+    let output = substitute_secrets_from("-", &[], r#"{ "sub1": "from-stdin" }"#)?;
+    assert_eq!(output, json!({ "foo": "bar", "bar": "from-stdin" }));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_substitutes_from_fd() -> Result<()> {
+    let output = substitute_secrets_from("fd:0", &[], r#"{ "sub1": "from-fd" }"#)?;
+    assert_eq!(output, json!({ "foo": "bar", "bar": "from-fd" }));
+    Ok(())
+}
+
+#[test]
+fn test_output_to_stdout() -> Result<()> {
+    let output = env_verify(
+        &[
+            "--schema",
+            "tests/yaml-multi-document/schema.yaml",
+            "--input",
+            "tests/yaml-multi-document/input.yaml",
+            "--env-vars",
+            "tests/yaml-multi-document/env-vars.yaml",
+            "--discriminator",
+            "kind",
+            "--document-schema",
+            "Service=tests/yaml-multi-document/schema-service.yaml",
+            "--document-schema",
+            "Deployment=tests/yaml-multi-document/schema-deployment.yaml",
+            "--output",
+            "-",
+        ],
+        &[],
+        "",
+    )?;
+
+    // Without --output-format, stdout gets the input format, here a multi-document YAML stream
+    let expected = std::fs::read_to_string("tests/yaml-multi-document/expected-output.yaml")?;
+    assert_eq!(
+        yaml_documents(&String::from_utf8(output.stdout)?)?,
+        yaml_documents(&expected)?
+    );
+
+    // Logging moves to stderr, so that it doesn't end up in the output
+    assert!(String::from_utf8(output.stderr)?.contains("Validation successful"));
+    Ok(())
+}